@@ -0,0 +1,319 @@
+use crate::{Command, Transaction};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::io::{BufRead, Read};
+
+/// Framing and serialization of write-ahead log records on disk.
+pub trait RecordCodec {
+    /// Encodes a transaction into a complete record, including any framing.
+    fn encode(&self, transaction: &Transaction) -> Result<Vec<u8>>;
+
+    /// Reads the next record, returning the transaction and the record's size in bytes, or `None`
+    /// at the end of the log.
+    fn decode(&self, reader: &mut dyn BufRead) -> Result<Option<(Transaction, usize)>>;
+}
+
+#[derive(Debug)]
+pub struct ChecksumMismatch {
+    expected: u32,
+    actual: u32,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Checksum mismatch: expected {:08x}, got {:08x}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+fn verify(expected: u32, data: &[u8]) -> Result<()> {
+    let actual = crc32fast::hash(data);
+    if expected != actual {
+        return Err(ChecksumMismatch { expected, actual }.into());
+    }
+    Ok(())
+}
+
+/// Newline delimited `{crc32}\t{json}` records.
+#[derive(Debug, Default)]
+pub struct JsonCodec;
+
+impl RecordCodec for JsonCodec {
+    fn encode(&self, transaction: &Transaction) -> Result<Vec<u8>> {
+        let json = serde_json::to_string(transaction)?;
+        Ok(format!("{:08x}\t{}\n", crc32fast::hash(json.as_bytes()), json).into_bytes())
+    }
+
+    fn decode(&self, reader: &mut dyn BufRead) -> Result<Option<(Transaction, usize)>> {
+        let mut buffer = String::new();
+        let length = reader.read_line(&mut buffer)?;
+        if length == 0 {
+            return Ok(None);
+        }
+
+        let line = buffer.strip_suffix('\n').unwrap_or(&buffer);
+        let json = match line.split_once('\t') {
+            Some((crc, json)) => {
+                verify(u32::from_str_radix(crc, 16)?, json.as_bytes())?;
+                json
+            }
+            // Records written before checksums were introduced are bare JSON
+            None => line,
+        };
+        Ok(Some((serde_json::from_str(json)?, length)))
+    }
+}
+
+/// A transaction as logged before transactions had a timestamp. Bincode has no notion of
+/// missing fields, so these records end right after the command.
+#[derive(Deserialize)]
+struct UntimedTransaction {
+    id: usize,
+    db: usize,
+    command: Command,
+}
+
+fn deserialize(payload: &[u8]) -> Result<Transaction> {
+    bincode::deserialize(payload).or_else(|e| match bincode::deserialize(payload) {
+        Ok(UntimedTransaction { id, db, command }) => Ok(Transaction {
+            id,
+            db,
+            command,
+            timestamp: 0,
+        }),
+        Err(_) => Err(e.into()),
+    })
+}
+
+/// Length prefixed bincode records: `[u32 length][u32 crc32][payload]`, little endian.
+#[derive(Debug, Default)]
+pub struct BinaryCodec;
+
+const HEADER_SIZE: usize = 8;
+
+impl RecordCodec for BinaryCodec {
+    fn encode(&self, transaction: &Transaction) -> Result<Vec<u8>> {
+        let payload = bincode::serialize(transaction)?;
+        let mut record = Vec::with_capacity(HEADER_SIZE + payload.len());
+        record.extend_from_slice(&u32::try_from(payload.len())?.to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+        Ok(record)
+    }
+
+    fn decode(&self, reader: &mut dyn BufRead) -> Result<Option<(Transaction, usize)>> {
+        if reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+
+        let mut header = [0; HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        let length = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+        // Don't trust the length enough to allocate it up front, it may come from a torn header
+        let mut payload = Vec::new();
+        reader.take(length as u64).read_to_end(&mut payload)?;
+        if payload.len() != length {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        verify(crc, &payload)?;
+        Ok(Some((deserialize(&payload)?, HEADER_SIZE + length)))
+    }
+}
+
+#[derive(Debug)]
+pub struct AuthenticationFailed;
+
+impl fmt::Display for AuthenticationFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Record failed authentication, it was tampered with or encrypted with another key"
+        )
+    }
+}
+
+impl std::error::Error for AuthenticationFailed {}
+
+pub const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+/// AES-256-GCM encrypted bincode records: `[u32 length][nonce][ciphertext + tag]`, where the
+/// length, little endian, covers the nonce, ciphertext and tag. The tag authenticates the record,
+/// so there is no separate checksum.
+///
+/// Nonces are random, which is safe for up to about 2^32 records per key.
+pub struct EncryptedCodec {
+    cipher: Aes256Gcm,
+}
+
+impl EncryptedCodec {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(key.into()),
+        }
+    }
+
+    /// Parses a key written as 64 hexadecimal digits.
+    pub fn parse_key(hex: &str) -> Result<[u8; KEY_SIZE]> {
+        let hex = hex.trim();
+        if hex.len() != KEY_SIZE * 2 || !hex.is_ascii() {
+            return Err(anyhow!(
+                "Encryption key must be {} hexadecimal digits",
+                KEY_SIZE * 2
+            ));
+        }
+        let mut key = [0; KEY_SIZE];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| anyhow!("Encryption key must be hexadecimal"))?;
+        }
+        Ok(key)
+    }
+}
+
+// Keeps the key out of logs
+impl fmt::Debug for EncryptedCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptedCodec")
+    }
+}
+
+impl RecordCodec for EncryptedCodec {
+    fn encode(&self, transaction: &Transaction) -> Result<Vec<u8>> {
+        let payload = bincode::serialize(transaction)?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, payload.as_slice())
+            .map_err(|_| anyhow!("Failed to encrypt record"))?;
+
+        let length = NONCE_SIZE + ciphertext.len();
+        let mut record = Vec::with_capacity(4 + length);
+        record.extend_from_slice(&u32::try_from(length)?.to_le_bytes());
+        record.extend_from_slice(&nonce);
+        record.extend_from_slice(&ciphertext);
+        Ok(record)
+    }
+
+    fn decode(&self, reader: &mut dyn BufRead) -> Result<Option<(Transaction, usize)>> {
+        if reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+
+        let mut header = [0; 4];
+        reader.read_exact(&mut header)?;
+        let length = u32::from_le_bytes(header) as usize;
+
+        let mut record = Vec::new();
+        reader.take(length as u64).read_to_end(&mut record)?;
+        if record.len() != length || length < NONCE_SIZE {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let (nonce, ciphertext) = record.split_at(NONCE_SIZE);
+        let payload = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| AuthenticationFailed)?;
+        Ok(Some((deserialize(&payload)?, 4 + length)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn binary_records_keep_newlines() {
+        let transactions: Vec<Transaction> = ["a\nb", "\n"]
+            .iter()
+            .enumerate()
+            .map(|(id, value)| Transaction {
+                id,
+                db: 0,
+                command: Command::Set {
+                    key: "key".into(),
+                    value: value.to_string(),
+                },
+                timestamp: 0,
+            })
+            .collect();
+        let mut records = vec![];
+        for transaction in &transactions {
+            records.extend(BinaryCodec.encode(transaction).unwrap());
+        }
+        let mut reader = Cursor::new(&records);
+        let mut decoded = vec![];
+        while let Some((transaction, length)) = BinaryCodec.decode(&mut reader).unwrap() {
+            assert_eq!(length, BinaryCodec.encode(&transaction).unwrap().len());
+            decoded.push(transaction.command);
+        }
+        let commands: Vec<Command> = transactions.into_iter().map(|t| t.command).collect();
+        assert_eq!(decoded, commands);
+    }
+
+    #[test]
+    fn binary_record_with_a_flipped_bit_fails_its_checksum() {
+        let transaction = Transaction {
+            id: 0,
+            db: 0,
+            command: Command::FlushAll,
+            timestamp: 0,
+        };
+        let mut record = BinaryCodec.encode(&transaction).unwrap();
+        *record.last_mut().unwrap() ^= 1;
+        let error = BinaryCodec.decode(&mut Cursor::new(&record)).unwrap_err();
+        assert!(error.downcast_ref::<ChecksumMismatch>().is_some());
+    }
+
+    fn encrypted() -> EncryptedCodec {
+        EncryptedCodec::new(&[7; KEY_SIZE])
+    }
+
+    #[test]
+    fn encrypted_records_round_trip() {
+        let transaction = Transaction {
+            id: 3,
+            db: 1,
+            command: Command::Set {
+                key: "key".into(),
+                value: "secret".into(),
+            },
+            timestamp: 42,
+        };
+        let record = encrypted().encode(&transaction).unwrap();
+        assert!(!record.windows(6).any(|window| window == b"secret"));
+        let (decoded, length) = encrypted()
+            .decode(&mut Cursor::new(&record))
+            .unwrap()
+            .unwrap();
+        assert_eq!(length, record.len());
+        assert_eq!((decoded.id, decoded.db), (3, 1));
+        assert_eq!(decoded.command, transaction.command);
+        assert_eq!(decoded.timestamp, 42);
+    }
+
+    #[test]
+    fn tampered_encrypted_record_fails_to_authenticate() {
+        let transaction = Transaction {
+            id: 0,
+            db: 0,
+            command: Command::FlushAll,
+            timestamp: 0,
+        };
+        let mut record = encrypted().encode(&transaction).unwrap();
+        record[4 + NONCE_SIZE] ^= 1;
+        let error = encrypted().decode(&mut Cursor::new(&record)).unwrap_err();
+        assert!(error.downcast_ref::<AuthenticationFailed>().is_some());
+    }
+}
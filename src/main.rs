@@ -1,26 +1,976 @@
-#![allow(dead_code)]
+mod clock;
+mod codec;
+mod metrics;
+mod net;
+mod resp;
+
 use anyhow::{anyhow, Result};
-use log::{info, warn};
+use clap::Parser;
+use clock::{Clock, SystemClock};
+use codec::{BinaryCodec, EncryptedCodec, JsonCodec, RecordCodec, KEY_SIZE};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::{debug, error, info, warn};
+use net::{ConnectionLimits, Protocol};
+use rand::{rngs::StdRng, seq::IteratorRandom, SeedableRng};
 use serde::{Deserialize, Serialize};
 use std::io::BufRead;
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::{BufReader, Seek, Write},
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
     slice::Iter,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+/// Turns the arguments after a command's name into the command. `parse_tokens` already checked
+/// there are as many as the command's arity asks for.
+type ParseFn = fn(&[&str]) -> Result<Command>;
+
+/// A command the server understands, the single place `parse_tokens` looks commands up in and
+/// COMMAND lists them from.
+struct CommandSpec {
+    name: &'static str,
+    /// Number of tokens including the name, like Redis negative if that's the minimum
+    arity: i64,
+    /// Whether it can change the data, for some commands only with certain arguments
+    write: bool,
+    parse: ParseFn,
+}
+
+impl CommandSpec {
+    const fn read(name: &'static str, arity: i64, parse: ParseFn) -> Self {
+        Self {
+            name,
+            arity,
+            write: false,
+            parse,
+        }
+    }
+
+    const fn write(name: &'static str, arity: i64, parse: ParseFn) -> Self {
+        Self {
+            name,
+            arity,
+            write: true,
+            parse,
+        }
+    }
+
+    fn find(name: &str) -> Option<&'static CommandSpec> {
+        COMMANDS.iter().find(|spec| spec.name == name)
+    }
+
+    fn accepts(&self, tokens: usize) -> bool {
+        match self.arity {
+            arity if arity < 0 => tokens as i64 >= -arity,
+            arity => tokens as i64 == arity,
+        }
+    }
+
+    fn reply(&self) -> Reply {
+        Reply::Array(vec![
+            Reply::Value(self.name.to_ascii_lowercase()),
+            Reply::Int(self.arity),
+            Reply::Value(if self.write { "write" } else { "readonly" }.into()),
+        ])
+    }
+}
+
+fn wrong_arguments(name: &str) -> anyhow::Error {
+    anyhow!("ERR wrong number of arguments for '{}'", name)
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
+
+fn pairs(values: &[&str]) -> Vec<(String, String)> {
+    values
+        .chunks(2)
+        .map(|pair| (pair[0].into(), pair[1].into()))
+        .collect()
+}
+
+const COMMANDS: &[CommandSpec] = &[
+    CommandSpec::read("GET", 2, |args| {
+        Ok(Command::Get {
+            key: args[0].into(),
+        })
+    }),
+    CommandSpec::write("SET", -3, |args| match *args {
+        [key, value] => Ok(Command::Set {
+            key: key.into(),
+            value: value.into(),
+        }),
+        [key, value, unit, ttl] => {
+            let ttl_ms = match unit.to_ascii_uppercase().as_str() {
+                "EX" => parse_integer::<u64>(ttl)?.saturating_mul(1000),
+                "PX" => parse_integer(ttl)?,
+                _ => return Err(anyhow!("ERR syntax error")),
+            };
+            if ttl_ms == 0 {
+                return Err(anyhow!("ERR invalid expire time in 'set' command"));
+            }
+            Ok(Command::SetEx {
+                key: key.into(),
+                value: value.into(),
+                ttl_ms,
+            })
+        }
+        _ => Err(anyhow!("ERR syntax error")),
+    }),
+    CommandSpec::write("DEL", 2, |args| {
+        Ok(Command::Delete {
+            key: args[0].into(),
+        })
+    }),
+    CommandSpec::write("DELETE", 2, |args| {
+        Ok(Command::Delete {
+            key: args[0].into(),
+        })
+    }),
+    CommandSpec::read("EXISTS", -2, |args| {
+        Ok(Command::Exists {
+            keys: strings(args),
+        })
+    }),
+    CommandSpec::write("INCR", 2, |args| {
+        Ok(Command::Incr {
+            key: args[0].into(),
+            delta: 1,
+        })
+    }),
+    CommandSpec::write("DECR", 2, |args| {
+        Ok(Command::Incr {
+            key: args[0].into(),
+            delta: -1,
+        })
+    }),
+    CommandSpec::write("INCRBY", 3, |args| {
+        Ok(Command::Incr {
+            key: args[0].into(),
+            delta: parse_integer(args[1])?,
+        })
+    }),
+    CommandSpec::write("EXPIRE", 3, |args| {
+        Ok(Command::Expire {
+            key: args[0].into(),
+            ttl_secs: parse_integer(args[1])?,
+        })
+    }),
+    CommandSpec::read("TTL", 2, |args| {
+        Ok(Command::Ttl {
+            key: args[0].into(),
+        })
+    }),
+    CommandSpec::read("KEYS", 2, |args| {
+        Ok(Command::Keys {
+            pattern: args[0].into(),
+        })
+    }),
+    CommandSpec::read("SCAN", -2, |args| match *args {
+        [cursor] => Ok(Command::Scan {
+            cursor: parse_integer(cursor)?,
+            count: 10,
+        }),
+        [cursor, keyword, count] if keyword.eq_ignore_ascii_case("COUNT") => Ok(Command::Scan {
+            cursor: parse_integer(cursor)?,
+            count: match parse_integer(count)? {
+                0 => return Err(anyhow!("ERR COUNT must be positive")),
+                count => count,
+            },
+        }),
+        _ => Err(wrong_arguments("SCAN")),
+    }),
+    CommandSpec::read("MGET", -2, |args| {
+        Ok(Command::MGet {
+            keys: strings(args),
+        })
+    }),
+    CommandSpec::write("MSET", -3, |args| match args.len() % 2 {
+        0 => Ok(Command::MSet { pairs: pairs(args) }),
+        _ => Err(wrong_arguments("MSET")),
+    }),
+    CommandSpec::read("DBSIZE", 1, |_| Ok(Command::DbSize)),
+    CommandSpec::write("FLUSHALL", 1, |_| Ok(Command::FlushAll)),
+    CommandSpec::write("SETNX", 3, |args| {
+        Ok(Command::SetNx {
+            key: args[0].into(),
+            value: args[1].into(),
+        })
+    }),
+    CommandSpec::write("GETSET", 3, |args| {
+        Ok(Command::GetSet {
+            key: args[0].into(),
+            value: args[1].into(),
+        })
+    }),
+    CommandSpec::write("GETDEL", 2, |args| {
+        Ok(Command::GetDel {
+            key: args[0].into(),
+        })
+    }),
+    CommandSpec::write("APPEND", 3, |args| {
+        Ok(Command::Append {
+            key: args[0].into(),
+            value: args[1].into(),
+        })
+    }),
+    CommandSpec::read("STRLEN", 2, |args| {
+        Ok(Command::StrLen {
+            key: args[0].into(),
+        })
+    }),
+    CommandSpec::write("RENAME", 3, |args| {
+        Ok(Command::Rename {
+            src: args[0].into(),
+            dst: args[1].into(),
+        })
+    }),
+    CommandSpec::read("SELECT", 2, |args| match parse_integer(args[0])? {
+        index if index < DATABASES => Ok(Command::Select { index }),
+        _ => Err(anyhow!("ERR DB index is out of range")),
+    }),
+    CommandSpec::read("INFO", 1, |_| Ok(Command::Info)),
+    CommandSpec::read("TYPE", 2, |args| {
+        Ok(Command::Type {
+            key: args[0].into(),
+        })
+    }),
+    CommandSpec::write("LPUSH", -3, |args| {
+        Ok(Command::LPush {
+            key: args[0].into(),
+            values: strings(&args[1..]),
+        })
+    }),
+    CommandSpec::write("RPUSH", -3, |args| {
+        Ok(Command::RPush {
+            key: args[0].into(),
+            values: strings(&args[1..]),
+        })
+    }),
+    CommandSpec::read("LRANGE", 4, |args| {
+        Ok(Command::LRange {
+            key: args[0].into(),
+            start: parse_integer(args[1])?,
+            stop: parse_integer(args[2])?,
+        })
+    }),
+    CommandSpec::write("HSET", -4, |args| match args.len() % 2 {
+        1 => Ok(Command::HSet {
+            key: args[0].into(),
+            pairs: pairs(&args[1..]),
+        }),
+        _ => Err(wrong_arguments("HSET")),
+    }),
+    CommandSpec::read("HGET", 3, |args| {
+        Ok(Command::HGet {
+            key: args[0].into(),
+            field: args[1].into(),
+        })
+    }),
+    CommandSpec::read("HGETALL", 2, |args| {
+        Ok(Command::HGetAll {
+            key: args[0].into(),
+        })
+    }),
+    CommandSpec::read("DUMP", 1, |_| Ok(Command::Dump)),
+    CommandSpec::write("RESTORE", 2, |args| {
+        Ok(Command::Restore {
+            dump: args[0].into(),
+        })
+    }),
+    CommandSpec::read("MULTI", 1, |_| Ok(Command::Multi)),
+    CommandSpec::read("EXEC", 1, |_| Ok(Command::Exec)),
+    CommandSpec::read("DISCARD", 1, |_| Ok(Command::Discard)),
+    CommandSpec::read("WATCH", -2, |args| {
+        Ok(Command::Watch {
+            keys: strings(args),
+        })
+    }),
+    CommandSpec::read("UNWATCH", 1, |_| Ok(Command::Unwatch)),
+    CommandSpec::read("SUBSCRIBE", 2, |args| {
+        Ok(Command::Subscribe {
+            channel: args[0].into(),
+        })
+    }),
+    CommandSpec::read("UNSUBSCRIBE", 2, |args| {
+        Ok(Command::Unsubscribe {
+            channel: args[0].into(),
+        })
+    }),
+    CommandSpec::read("PUBLISH", 3, |args| {
+        Ok(Command::Publish {
+            channel: args[0].into(),
+            message: args[1].into(),
+        })
+    }),
+    CommandSpec::write("EXPIREAT", 3, |args| {
+        Ok(Command::ExpireAt {
+            key: args[0].into(),
+            at: parse_integer::<u64>(args[1])?.saturating_mul(1000),
+        })
+    }),
+    CommandSpec::write("PERSIST", 2, |args| {
+        Ok(Command::Persist {
+            key: args[0].into(),
+        })
+    }),
+    CommandSpec::write("COPY", -3, |args| match *args {
+        [src, dst, ..] if src == dst => {
+            Err(anyhow!("ERR source and destination objects are the same"))
+        }
+        [src, dst] => Ok(Command::Copy {
+            src: src.into(),
+            dst: dst.into(),
+            replace: false,
+        }),
+        [src, dst, keyword] if keyword.eq_ignore_ascii_case("REPLACE") => Ok(Command::Copy {
+            src: src.into(),
+            dst: dst.into(),
+            replace: true,
+        }),
+        _ => Err(wrong_arguments("COPY")),
+    }),
+    CommandSpec::read("RANDOMKEY", 1, |_| Ok(Command::RandomKey)),
+    CommandSpec::read("GETRANGE", 4, parse_get_range),
+    CommandSpec::read("SUBSTR", 4, parse_get_range),
+    CommandSpec::write("SETRANGE", 4, |args| {
+        let offset = parse_integer::<usize>(args[1])?;
+        if offset
+            .checked_add(args[2].len())
+            .is_none_or(|end| end > MAX_STRING_LEN)
+        {
+            return Err(anyhow!(STRING_TOO_LONG));
+        }
+        Ok(Command::SetRange {
+            key: args[0].into(),
+            offset,
+            value: args[2].into(),
+        })
+    }),
+    CommandSpec::write("CAS", 4, |args| {
+        Ok(Command::Cas {
+            key: args[0].into(),
+            expected: args[1].into(),
+            new: args[2].into(),
+        })
+    }),
+    CommandSpec::write("DECRBY", 3, |args| {
+        Ok(Command::Incr {
+            key: args[0].into(),
+            delta: parse_integer::<i64>(args[1])?
+                .checked_neg()
+                .ok_or_else(|| anyhow!("ERR decrement would overflow"))?,
+        })
+    }),
+    CommandSpec::read("TOUCH", -2, |args| {
+        Ok(Command::Touch {
+            keys: strings(args),
+        })
+    }),
+    CommandSpec::read("OBJECT", 3, |args| {
+        match args[0].to_ascii_uppercase().as_str() {
+            "IDLETIME" => Ok(Command::ObjectIdleTime {
+                key: args[1].into(),
+            }),
+            _ => Err(wrong_arguments("OBJECT")),
+        }
+    }),
+    CommandSpec::write("UNLINK", -2, |args| {
+        Ok(Command::Unlink {
+            keys: strings(args),
+        })
+    }),
+    CommandSpec::read("PING", -1, |args| match *args {
+        [] => Ok(Command::Ping { message: None }),
+        [message] => Ok(Command::Ping {
+            message: Some(message.into()),
+        }),
+        _ => Err(wrong_arguments("PING")),
+    }),
+    CommandSpec::read("ECHO", 2, |args| {
+        Ok(Command::Echo {
+            message: args[0].into(),
+        })
+    }),
+    CommandSpec::write("GETEX", -2, |args| match *args {
+        [key] => Ok(Command::GetEx {
+            key: key.into(),
+            ttl: None,
+        }),
+        [key, keyword] if keyword.eq_ignore_ascii_case("PERSIST") => Ok(Command::GetEx {
+            key: key.into(),
+            ttl: Some(ExpiryOpt::Persist),
+        }),
+        [key, unit, time] => {
+            let time = parse_integer::<u64>(time)?;
+            let ttl = match unit.to_ascii_uppercase().as_str() {
+                "EX" => ExpiryOpt::In(time.saturating_mul(1000)),
+                "PX" => ExpiryOpt::In(time),
+                "EXAT" => ExpiryOpt::At(time.saturating_mul(1000)),
+                "PXAT" => ExpiryOpt::At(time),
+                _ => return Err(anyhow!("ERR syntax error")),
+            };
+            if time == 0 {
+                return Err(anyhow!("ERR invalid expire time in 'getex' command"));
+            }
+            Ok(Command::GetEx {
+                key: key.into(),
+                ttl: Some(ttl),
+            })
+        }
+        _ => Err(anyhow!("ERR syntax error")),
+    }),
+    CommandSpec::read("LASTID", 1, |_| Ok(Command::LastId)),
+    CommandSpec::write("DEBUG", 2, |args| {
+        match args[0].to_ascii_uppercase().as_str() {
+            "EXPIRE-SWEEP" => Ok(Command::ExpireSweep),
+            _ => Err(anyhow!("ERR unknown subcommand '{}'", args[0])),
+        }
+    }),
+    CommandSpec::read("COMMAND", 1, |_| Ok(Command::ListCommands)),
+    CommandSpec::read("HELP", 1, |_| Ok(Command::ListCommands)),
+];
+
+fn parse_get_range(args: &[&str]) -> Result<Command> {
+    Ok(Command::GetRange {
+        key: args[0].into(),
+        start: parse_integer(args[1])?,
+        end: parse_integer(args[2])?,
+    })
+}
+
+/// How GETEX changes the expiry of its key.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+enum ExpiryOpt {
+    /// EX or PX, in milliseconds from now. Resolved into `At` before it's logged.
+    In(u64),
+    /// EXAT or PXAT, as a Unix timestamp in milliseconds
+    At(u64),
+    Persist,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
-    Get { key: String },
+    Set {
+        key: String,
+        value: String,
+    },
+    /// SET with EX or PX. Resolved into `SetExAt` before it's logged.
+    SetEx {
+        key: String,
+        value: String,
+        ttl_ms: u64,
+    },
+    SetExAt {
+        key: String,
+        value: String,
+        at: u64,
+    },
+    SetNx {
+        key: String,
+        value: String,
+    },
+    /// Compare-and-swap. A missing key never matches, use SETNX to create one.
+    Cas {
+        key: String,
+        expected: String,
+        new: String,
+    },
+    GetSet {
+        key: String,
+        value: String,
+    },
+    Append {
+        key: String,
+        value: String,
+    },
+    SetRange {
+        key: String,
+        offset: usize,
+        value: String,
+    },
+    Get {
+        key: String,
+    },
+    /// A GET that also changes the expiry of the key, unless `ttl` is None. Only logged when it
+    /// does, with the expiry resolved to a timestamp.
+    GetEx {
+        key: String,
+        ttl: Option<ExpiryOpt>,
+    },
+    Delete {
+        key: String,
+    },
+    /// Removes the keys right away, but drops their values off the write path. See `reclaim`.
+    Unlink {
+        keys: Vec<String>,
+    },
+    GetDel {
+        key: String,
+    },
+    Rename {
+        src: String,
+        dst: String,
+    },
+    Copy {
+        src: String,
+        dst: String,
+        replace: bool,
+    },
+    /// Counts a key given more than once each time, like Redis.
+    Exists {
+        keys: Vec<String>,
+    },
+    StrLen {
+        key: String,
+    },
+    /// Byte offsets, like STRLEN and Redis. See `Database::query`.
+    GetRange {
+        key: String,
+        start: i64,
+        end: i64,
+    },
+    Type {
+        key: String,
+    },
+    Incr {
+        key: String,
+        delta: i64,
+    },
+    Expire {
+        key: String,
+        ttl_secs: u64,
+    },
+    ExpireAt {
+        key: String,
+        at: u64,
+    },
+    Ttl {
+        key: String,
+    },
+    Persist {
+        key: String,
+    },
+    Keys {
+        pattern: String,
+    },
+    Scan {
+        cursor: usize,
+        count: usize,
+    },
+    MGet {
+        keys: Vec<String>,
+    },
+    /// Only marks the keys as recently used, which isn't logged.
+    Touch {
+        keys: Vec<String>,
+    },
+    /// OBJECT IDLETIME, doesn't count as an access itself.
+    ObjectIdleTime {
+        key: String,
+    },
+    MSet {
+        pairs: Vec<(String, String)>,
+    },
+    LPush {
+        key: String,
+        values: Vec<String>,
+    },
+    RPush {
+        key: String,
+        values: Vec<String>,
+    },
+    LRange {
+        key: String,
+        start: i64,
+        stop: i64,
+    },
+    HSet {
+        key: String,
+        pairs: Vec<(String, String)>,
+    },
+    HGet {
+        key: String,
+        field: String,
+    },
+    HGetAll {
+        key: String,
+    },
+    DbSize,
+    RandomKey,
+    FlushAll,
+    Select {
+        index: usize,
+    },
+    Info,
+    Dump,
+    Restore {
+        dump: String,
+    },
+    Multi,
+    Exec,
+    Discard,
+    Watch {
+        keys: Vec<String>,
+    },
+    Unwatch,
+    Subscribe {
+        channel: String,
+    },
+    Unsubscribe {
+        channel: String,
+    },
+    Publish {
+        channel: String,
+        message: String,
+    },
+    /// Answered without waiting on the state, so health checks go through while a replica
+    /// catches up on the log.
+    Ping {
+        message: Option<String>,
+    },
+    Echo {
+        message: String,
+    },
+    /// ID of the last transaction applied, see `Log::last_id`. A replica is as far behind its
+    /// primary as the difference between theirs.
+    LastId,
+    /// COMMAND or HELP, describing every command in `COMMANDS`
+    ListCommands,
+    /// DEBUG EXPIRE-SWEEP, deleting a batch of expired keys right away like the sweeps of
+    /// `Server::spawn_expirer` do. Lets tests expire keys without waiting on the sweeper.
+    ExpireSweep,
+    /// Mutations logged as one transaction, so they're recovered all together or not at all. Every
+    /// command is paired with the database it applies to.
+    Batch {
+        commands: Vec<(usize, Command)>,
+    },
+    /// Logged for unrecognized queries by versions that executed them as no-ops, rather than
+    /// reject them, so their logs still replay. Nothing parses to it, and as it doesn't mutate
+    /// anything, it's never logged nor takes a transaction ID.
     Nop,
 }
 
+impl Command {
+    /// Whether the command changes state and therefore has to go through the write-ahead log.
+    fn is_mutating(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::SetEx { .. }
+                | Command::SetExAt { .. }
+                | Command::SetNx { .. }
+                | Command::Cas { .. }
+                | Command::GetSet { .. }
+                | Command::Append { .. }
+                | Command::SetRange { .. }
+                | Command::MSet { .. }
+                | Command::LPush { .. }
+                | Command::RPush { .. }
+                | Command::HSet { .. }
+                | Command::GetEx { ttl: Some(_), .. }
+                | Command::Delete { .. }
+                | Command::Unlink { .. }
+                | Command::GetDel { .. }
+                | Command::Rename { .. }
+                | Command::Copy { .. }
+                | Command::Incr { .. }
+                | Command::Expire { .. }
+                | Command::ExpireAt { .. }
+                | Command::Persist { .. }
+                | Command::FlushAll
+                | Command::Batch { .. }
+        )
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        match self {
+            Command::Set { key, .. }
+            | Command::SetEx { key, .. }
+            | Command::SetExAt { key, .. }
+            | Command::SetNx { key, .. }
+            | Command::Cas { key, .. }
+            | Command::GetSet { key, .. }
+            | Command::Append { key, .. }
+            | Command::SetRange { key, .. }
+            | Command::Get { key }
+            | Command::GetEx { key, .. }
+            | Command::Delete { key }
+            | Command::GetDel { key }
+            | Command::StrLen { key }
+            | Command::GetRange { key, .. }
+            | Command::Type { key }
+            | Command::LPush { key, .. }
+            | Command::RPush { key, .. }
+            | Command::LRange { key, .. }
+            | Command::HSet { key, .. }
+            | Command::HGet { key, .. }
+            | Command::HGetAll { key }
+            | Command::Incr { key, .. }
+            | Command::Expire { key, .. }
+            | Command::ExpireAt { key, .. }
+            | Command::Persist { key }
+            | Command::ObjectIdleTime { key }
+            | Command::Ttl { key } => vec![key],
+            Command::Rename { src, dst } | Command::Copy { src, dst, .. } => vec![src, dst],
+            Command::MGet { keys }
+            | Command::Exists { keys }
+            | Command::Touch { keys }
+            | Command::Unlink { keys } => keys.iter().map(String::as_str).collect(),
+            Command::MSet { pairs } => pairs.iter().map(|(key, _)| key.as_str()).collect(),
+            Command::Keys { .. }
+            | Command::Scan { .. }
+            | Command::DbSize
+            | Command::RandomKey
+            | Command::FlushAll
+            | Command::Select { .. }
+            | Command::Info
+            | Command::Dump
+            | Command::Restore { .. }
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch { .. }
+            | Command::Unwatch
+            | Command::Subscribe { .. }
+            | Command::Unsubscribe { .. }
+            | Command::Publish { .. }
+            | Command::Ping { .. }
+            | Command::Echo { .. }
+            | Command::LastId
+            | Command::ListCommands
+            | Command::ExpireSweep
+            | Command::Batch { .. }
+            | Command::Nop => {
+                vec![]
+            }
+        }
+    }
+
+    /// Keys the command can create, as opposed to only read, change or delete.
+    fn created_keys(&self) -> Vec<&str> {
+        match self {
+            Command::Set { key, .. }
+            | Command::SetEx { key, .. }
+            | Command::SetExAt { key, .. }
+            | Command::SetNx { key, .. }
+            | Command::GetSet { key, .. }
+            | Command::Append { key, .. }
+            | Command::SetRange { key, .. }
+            | Command::LPush { key, .. }
+            | Command::RPush { key, .. }
+            | Command::HSet { key, .. }
+            | Command::Incr { key, .. } => vec![key],
+            Command::Rename { dst, .. } | Command::Copy { dst, .. } => vec![dst],
+            Command::MSet { pairs } => pairs.iter().map(|(key, _)| key.as_str()).collect(),
+            Command::Batch { commands } => commands
+                .iter()
+                .flat_map(|(_, command)| command.created_keys())
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Values the command stores, including list elements and hash fields.
+    fn values(&self) -> Vec<&str> {
+        match self {
+            Command::Set { value, .. }
+            | Command::SetEx { value, .. }
+            | Command::SetExAt { value, .. }
+            | Command::SetNx { value, .. }
+            | Command::GetSet { value, .. }
+            | Command::Append { value, .. }
+            | Command::SetRange { value, .. }
+            | Command::Cas { new: value, .. } => vec![value],
+            Command::LPush { values, .. } | Command::RPush { values, .. } => {
+                values.iter().map(String::as_str).collect()
+            }
+            Command::MSet { pairs } => pairs.iter().map(|(_, value)| value.as_str()).collect(),
+            Command::HSet { pairs, .. } => pairs
+                .iter()
+                .flat_map(|(field, value)| [field.as_str(), value.as_str()])
+                .collect(),
+            Command::Batch { commands } => commands
+                .iter()
+                .flat_map(|(_, command)| command.values())
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Name the command is counted under in INFO.
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Set { .. } | Command::SetEx { .. } | Command::SetExAt { .. } => "set",
+            Command::SetNx { .. } => "setnx",
+            Command::Cas { .. } => "cas",
+            Command::GetSet { .. } => "getset",
+            Command::Append { .. } => "append",
+            Command::SetRange { .. } => "setrange",
+            Command::Get { .. } => "get",
+            Command::GetEx { .. } => "getex",
+            Command::Delete { .. } => "del",
+            Command::Unlink { .. } => "unlink",
+            Command::GetDel { .. } => "getdel",
+            Command::Rename { .. } => "rename",
+            Command::Copy { .. } => "copy",
+            Command::Exists { .. } => "exists",
+            Command::StrLen { .. } => "strlen",
+            Command::GetRange { .. } => "getrange",
+            Command::Type { .. } => "type",
+            Command::Incr { .. } => "incr",
+            Command::Expire { .. } | Command::ExpireAt { .. } => "expire",
+            Command::Ttl { .. } => "ttl",
+            Command::Persist { .. } => "persist",
+            Command::Keys { .. } => "keys",
+            Command::Scan { .. } => "scan",
+            Command::MGet { .. } => "mget",
+            Command::Touch { .. } => "touch",
+            Command::ObjectIdleTime { .. } => "object",
+            Command::MSet { .. } => "mset",
+            Command::LPush { .. } => "lpush",
+            Command::RPush { .. } => "rpush",
+            Command::LRange { .. } => "lrange",
+            Command::HSet { .. } => "hset",
+            Command::HGet { .. } => "hget",
+            Command::HGetAll { .. } => "hgetall",
+            Command::DbSize => "dbsize",
+            Command::RandomKey => "randomkey",
+            Command::FlushAll => "flushall",
+            Command::Select { .. } => "select",
+            Command::Info => "info",
+            Command::Dump => "dump",
+            Command::Restore { .. } => "restore",
+            Command::Multi => "multi",
+            Command::Exec => "exec",
+            Command::Discard => "discard",
+            Command::Watch { .. } => "watch",
+            Command::Unwatch => "unwatch",
+            Command::Ping { .. } => "ping",
+            Command::Echo { .. } => "echo",
+            Command::LastId => "lastid",
+            Command::ListCommands => "command",
+            Command::ExpireSweep => "debug",
+            Command::Subscribe { .. } => "subscribe",
+            Command::Unsubscribe { .. } => "unsubscribe",
+            Command::Publish { .. } => "publish",
+            Command::Batch { .. } => "batch",
+            Command::Nop => "nop",
+        }
+    }
+
+    /// The keys the command changed, judging by its reply, along with the keyspace notification
+    /// event for each.
+    fn events(&self, reply: &Reply) -> Vec<(&str, &'static str)> {
+        match (self, reply) {
+            (_, Reply::Error(_)) => vec![],
+            (Command::Set { key, .. } | Command::GetSet { key, .. }, _) => vec![(key, "set")],
+            (Command::SetExAt { key, .. }, _) => vec![(key, "set"), (key, "expire")],
+            (Command::SetNx { key, .. } | Command::Cas { key, .. }, Reply::Int(1)) => {
+                vec![(key, "set")]
+            }
+            (Command::Append { key, .. }, _) => vec![(key, "append")],
+            (Command::SetRange { key, .. }, _) => vec![(key, "setrange")],
+            (Command::MSet { pairs }, _) => {
+                pairs.iter().map(|(key, _)| (key.as_str(), "set")).collect()
+            }
+            (Command::LPush { key, .. }, _) => vec![(key, "lpush")],
+            (Command::RPush { key, .. }, _) => vec![(key, "rpush")],
+            (Command::HSet { key, .. }, _) => vec![(key, "hset")],
+            (Command::Delete { key } | Command::GetDel { key }, reply) if *reply != Reply::Nil => {
+                vec![(key, "del")]
+            }
+            (Command::Rename { src, dst }, _) => vec![(src, "rename_from"), (dst, "rename_to")],
+            (Command::Copy { dst, .. }, Reply::Int(1)) => vec![(dst, "copy_to")],
+            (Command::Incr { key, .. }, _) => vec![(key, "incrby")],
+            (Command::ExpireAt { key, .. }, Reply::Int(1)) => vec![(key, "expire")],
+            (Command::Persist { key }, Reply::Int(1)) => vec![(key, "persist")],
+            (
+                Command::GetEx {
+                    key,
+                    ttl: Some(ttl),
+                },
+                reply,
+            ) if *reply != Reply::Nil => match ttl {
+                ExpiryOpt::Persist => vec![(key, "persist")],
+                _ => vec![(key, "expire")],
+            },
+            _ => vec![],
+        }
+    }
+
+    /// Like Redis, an expiry that has already passed deletes the key right away rather than leave
+    /// it behind expired. This is the delete to log and apply right after the command.
+    fn immediate_delete(&self, now: u64) -> Option<Command> {
+        match self {
+            Command::ExpireAt { key, at }
+            | Command::GetEx {
+                key,
+                ttl: Some(ExpiryOpt::At(at)),
+            } if *at <= now => Some(Command::Delete { key: key.clone() }),
+            _ => None,
+        }
+    }
+
+    /// Replaces time relative arguments with absolute Unix timestamps in milliseconds, so replaying
+    /// the command from the write-ahead log gives the same result regardless of when it happens.
+    fn resolve(self, now: u64) -> Self {
+        match self {
+            Command::Expire { key, ttl_secs } => Command::ExpireAt {
+                key,
+                at: now.saturating_add(ttl_secs.saturating_mul(1000)),
+            },
+            Command::SetEx { key, value, ttl_ms } => Command::SetExAt {
+                key,
+                value,
+                at: now.saturating_add(ttl_ms),
+            },
+            Command::GetEx {
+                key,
+                ttl: Some(ExpiryOpt::In(ttl_ms)),
+            } => Command::GetEx {
+                key,
+                ttl: Some(ExpiryOpt::At(now.saturating_add(ttl_ms))),
+            },
+            command => command,
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 struct Transaction {
     id: usize,
+    /// The database the command applies to. Logs from before multiple databases were supported
+    /// only ever used the first. Unused for a `Command::Batch`, which has a database per command.
+    #[serde(default)]
+    db: usize,
     command: Command,
+    /// When the transaction was logged, as a Unix timestamp in milliseconds. The same time its
+    /// relative expiries were resolved against. 0 in logs from before it was recorded.
+    #[serde(default)]
+    timestamp: u64,
+}
+
+impl Transaction {
+    /// Every database the transaction applies to.
+    fn databases(&self) -> Vec<usize> {
+        match &self.command {
+            Command::Batch { commands } => commands.iter().map(|(db, _)| *db).collect(),
+            _ => vec![self.db],
+        }
+    }
 }
 
 trait WriteAheadLog {
@@ -30,14 +980,91 @@ trait WriteAheadLog {
 
     fn replay(&mut self) -> Result<Self::ReplayIterator<'_>>;
 
+    /// Replays only the transactions with an ID of at least `start_id`. Implementations should
+    /// avoid reading what comes before where they can.
+    fn replay_from(
+        &mut self,
+        start_id: usize,
+    ) -> Result<impl Iterator<Item = Result<Transaction>> + '_> {
+        Ok(self.replay()?.filter(move |result| {
+            result
+                .as_ref()
+                .map_or(true, |transaction| transaction.id >= start_id)
+        }))
+    }
+
     fn append(&mut self, transaction: &Transaction) -> Result<()>;
+
+    /// Appends several transactions at once. Implementations should make this cheaper than
+    /// appending one by one, e.g. by syncing only once.
+    fn append_batch(&mut self, transactions: &[Transaction]) -> Result<()> {
+        for transaction in transactions {
+            self.append(transaction)?;
+        }
+        Ok(())
+    }
+
+    /// Appends without syncing, whatever the durability. Call `flush` once done appending.
+    fn append_unsynced(&mut self, transactions: &[Transaction]) -> Result<()> {
+        self.append_batch(transactions)
+    }
+
+    /// Forces everything appended so far to stable storage.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called every `SYNC_TICK_INTERVAL`, so syncs that are only due after some time happen even
+    /// when nothing else is appended.
+    fn tick(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Atomically replaces the entire log with the given transactions.
+    fn rewrite(&mut self, transactions: &[Transaction]) -> Result<()>;
+
+    /// Drops transaction `id` and the ones after it, leaving the IDs of the others as they are.
+    /// Implementations can keep what they drop around, as long as it's out of the log.
+    fn discard_from(&mut self, id: usize) -> Result<()> {
+        let kept = self
+            .replay()?
+            .take_while(|result| result.as_ref().map_or(true, |t| t.id < id))
+            .collect::<Result<Vec<_>>>()?;
+        self.rewrite(&kept)
+    }
+
+    /// Size of the log in bytes, 0 if it isn't stored as bytes.
+    fn size(&self) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Counts the bytes of the log the last replay got past, including those it skipped, so
+    /// recovery can report its progress against `size` while it replays. None if the log can't
+    /// tell.
+    fn replayed_bytes(&self) -> Option<Arc<AtomicU64>> {
+        None
+    }
+
+    /// Times the log synced appended records to stable storage, None if it never does.
+    fn syncs(&self) -> Option<u64> {
+        None
+    }
+
+    /// Called once a snapshot holds every transaction before `id`, so the log can drop those it
+    /// can cheaply, like whole segments. Must keep transaction `id - 1`, as recovery checks the
+    /// log continues from there.
+    fn checkpoint(&mut self, _id: usize) -> Result<()> {
+        Ok(())
+    }
 }
 
 // In memory
 
+/// Keeps every transaction appended, unless created `without_history`.
 #[derive(Debug)]
 struct InMemoryWriteAheadLog {
     data: Vec<Transaction>,
+    history: bool,
 }
 
 struct InMemoryReplayIterator<'a> {
@@ -54,7 +1081,19 @@ impl Iterator for InMemoryReplayIterator<'_> {
 
 impl InMemoryWriteAheadLog {
     fn new() -> Self {
-        Self { data: vec![] }
+        Self {
+            data: vec![],
+            history: true,
+        }
+    }
+
+    /// Keeps only the last transaction, as the others otherwise grow the log without bound. The
+    /// state holds everything there is, and recovering again only needs the last transaction to
+    /// check the log continues from there.
+    fn without_history(mut self) -> Self {
+        self.data = vec![];
+        self.history = false;
+        self
     }
 }
 
@@ -62,10 +1101,30 @@ impl WriteAheadLog for InMemoryWriteAheadLog {
     type ReplayIterator<'a> = InMemoryReplayIterator<'a>;
 
     fn append(&mut self, transaction: &Transaction) -> Result<()> {
+        if !self.history {
+            self.data.clear();
+        }
         self.data.push(transaction.clone());
         Ok(())
     }
 
+    fn rewrite(&mut self, transactions: &[Transaction]) -> Result<()> {
+        let kept = match self.history {
+            true => transactions,
+            false => &transactions[transactions.len().saturating_sub(1)..],
+        };
+        self.data = kept.to_vec();
+        Ok(())
+    }
+
+    /// Drops the transactions before `id - 1`, so a long running server with snapshots only
+    /// keeps those after the last one.
+    fn checkpoint(&mut self, id: usize) -> Result<()> {
+        let kept = self.data.partition_point(|t| t.id + 1 < id);
+        self.data.drain(..kept);
+        Ok(())
+    }
+
     fn replay(&mut self) -> Result<Self::ReplayIterator<'_>> {
         Ok(InMemoryReplayIterator {
             iter: self.data.iter(),
@@ -75,186 +1134,5435 @@ impl WriteAheadLog for InMemoryWriteAheadLog {
 
 // On disk
 
-#[derive(Debug)]
-struct OnDiskWriteAheadLog {
-    file: File,
+/// When appended records are fsynced to disk. Without a write buffer, records are handed to the
+/// OS right away, so a crash of just the process loses nothing; the modes differ in what an OS
+/// crash or power loss can take with it. See `OnDiskWriteAheadLog::with_write_buffer` for what
+/// buffering changes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Durability {
+    /// Sync after every append. Nothing that was acknowledged is lost.
+    Always,
+    /// Sync at most once every N seconds, when appending or on `WriteAheadLog::tick`. Up to N
+    /// seconds of acknowledged writes, plus the time between ticks, can be lost.
+    EverySecs(u64),
+    /// Never sync, leave writeback to the OS. Anything not yet written back can be lost.
+    Never,
 }
-struct OnDiskReplayIterator<'a> {
-    reader: BufReader<&'a mut File>,
-    error: bool,
-}
-
-impl Iterator for OnDiskReplayIterator<'_> {
-    type Item = Result<Transaction>;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.error {
-            return None;
-        }
+impl FromStr for Durability {
+    type Err = anyhow::Error;
 
-        let mut buffer = String::new();
-        match self.reader.read_line(&mut buffer) {
-            Ok(length) if length > 0 => match serde_json::from_str(&buffer) {
-                Ok(t) => Some(Ok(t)),
-                Err(e) => {
-                    self.error = true;
-                    Some(Err(e.into()))
-                }
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "always" => Ok(Durability::Always),
+            "never" => Ok(Durability::Never),
+            _ => match s.parse() {
+                Ok(secs) if secs > 0 => Ok(Durability::EverySecs(secs)),
+                _ => Err(anyhow!(
+                    "Unknown durability '{}', expected 'always', 'never' or a number of seconds",
+                    s
+                )),
             },
-            Ok(_) => None,
-            Err(e) => {
-                self.error = true;
-                Some(Err(e.into()))
-            }
         }
     }
 }
 
-impl OnDiskWriteAheadLog {
-    fn new(f: File) -> Self {
-        Self { file: f }
-    }
+/// Log stored in numbered segment files next to `path`, `<path>.00001`, `<path>.00002`, ...
+/// Appends go to the last segment, and roll over to a new one once it reaches the maximum size.
+/// Sealed segments can be gzipped, to `<path>.00001.gz` and so on.
+///
+/// Records are streamed to the files and never kept in memory. What the log does keep grows with
+/// the last segment only: its offset index, and up to the write buffer of records.
+#[derive(Debug)]
+struct OnDiskWriteAheadLog<C: RecordCodec = JsonCodec> {
+    path: PathBuf,
+    /// Number of the last segment, the one appended to.
+    segment: u32,
+    /// Buffers appends for up to `write_buffer` bytes, see `with_write_buffer`.
+    file: BufWriter<File>,
+    write_buffer: usize,
+    /// Size of the last segment in bytes, including what's still buffered.
+    size: u64,
+    max_segment_size: Option<u64>,
+    compress_segments: bool,
+    /// Opened by a replica following a log another server writes to.
+    read_only: bool,
+    codec: C,
+    durability: Durability,
+    clock: Arc<dyn Clock>,
+    last_sync: u64,
+    /// Whether everything appended has been synced, or written out for `Durability::Never`.
+    synced: bool,
+    /// Times the log was synced to disk, see `WriteAheadLog::syncs`.
+    syncs: u64,
+    index: OffsetIndex,
+    replayed: Arc<AtomicU64>,
 }
 
-impl WriteAheadLog for OnDiskWriteAheadLog {
-    type ReplayIterator<'a> = OnDiskReplayIterator<'a>;
+const INDEX_INTERVAL: usize = 1000;
 
-    fn append(&mut self, transaction: &Transaction) -> Result<()> {
-        writeln!(self.file, "{}", serde_json::to_string(transaction)?)?;
-        self.file.sync_data()?;
-        Ok(())
-    }
+/// Transaction IDs and the offsets of their records, in ascending order.
+type Offsets = Vec<(usize, u64)>;
 
-    fn replay(&mut self) -> Result<Self::ReplayIterator<'_>> {
-        self.file.rewind()?;
-        Ok(OnDiskReplayIterator {
-            reader: BufReader::new(&mut self.file),
-            error: false,
-        })
-    }
+/// Byte offsets of every `INDEX_INTERVAL`th transaction in the last segment, so replaying from a
+/// transaction ID can seek close to it rather than read the segment from the start. It's kept in
+/// memory only, and rebuilt by replaying the segment.
+#[derive(Debug, Default)]
+struct OffsetIndex {
+    segment: u32,
+    entries: Offsets,
 }
 
-#[derive(Debug)]
-struct State {
-    kv: HashMap<String, String>,
-}
+impl OffsetIndex {
+    fn record(&mut self, segment: u32, id: usize, offset: u64) {
+        if segment != self.segment {
+            self.segment = segment;
+            self.entries.clear();
+        }
+        if id.is_multiple_of(INDEX_INTERVAL)
+            && self.entries.last().is_none_or(|(last, _)| id > *last)
+        {
+            self.entries.push((id, offset));
+        }
+    }
 
-impl State {
-    fn apply(&mut self, transaction: &Transaction) -> String {
-        let default = "".into();
-        match &transaction.command {
-            Command::Set { key, value } => {
-                self.kv.insert(key.into(), value.into());
-                default
-            }
-            Command::Get { key } => self.kv.get(key).unwrap_or(&default).into(),
-            _ => default,
+    /// Offset in the segment to start reading from to find transaction `id`.
+    fn seek(&self, segment: u32, id: usize) -> u64 {
+        if segment != self.segment {
+            return 0;
         }
+        self.entries
+            .iter()
+            .rev()
+            .find(|(indexed, _)| *indexed <= id)
+            .map_or(0, |(_, offset)| *offset)
+    }
+
+    fn truncate(&mut self, offset: u64) {
+        self.entries.retain(|(_, indexed)| *indexed < offset);
     }
 }
 
-#[derive(Debug)]
-struct Server<W: WriteAheadLog> {
-    transaction_id: usize,
-    write_ahead_log: W,
-    state: State,
+struct OnDiskReplayIterator<'a, C: RecordCodec> {
+    /// Numbers and paths of the segments that haven't been read yet, in order. The last one is
+    /// `active`.
+    segments: std::vec::IntoIter<(u32, PathBuf)>,
+    /// Number and path of the segment being read.
+    segment: u32,
+    path: PathBuf,
+    /// Number of records read from the segment so far, unknown when reading started at an
+    /// indexed offset instead of the start.
+    records: Option<usize>,
+    /// ID of the last transaction read.
+    last_id: Option<usize>,
+    reader: Option<BufReader<Box<dyn Read>>>,
+    /// None for a read-only log.
+    active: Option<&'a mut BufWriter<File>>,
+    codec: &'a C,
+    index: &'a mut OffsetIndex,
+    /// Where to start reading the last segment.
+    start_offset: u64,
+    offset: u64,
+    /// Bytes of the segments before the one being read, see `WriteAheadLog::replayed_bytes`.
+    passed: u64,
+    replayed: Arc<AtomicU64>,
+    error: bool,
 }
 
-impl<W: WriteAheadLog> Server<W> {
-    fn new(write_ahead_log: W) -> Self {
-        Self {
-            transaction_id: 0,
-            write_ahead_log,
-            state: State { kv: HashMap::new() },
+impl<C: RecordCodec> Iterator for OnDiskReplayIterator<'_, C> {
+    type Item = Result<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error {
+            return None;
         }
-    }
 
-    fn recover(&mut self) -> Result<()> {
-        for result in self.write_ahead_log.replay()? {
-            let transaction = result?;
+        loop {
+            let reader = match &mut self.reader {
+                Some(reader) => reader,
+                None => {
+                    let (segment, path) = self.segments.next()?;
+                    self.segment = segment;
+                    self.path = path;
+                    self.offset = if self.segments.len() == 0 {
+                        self.start_offset
+                    } else {
+                        0
+                    };
+                    self.records = (self.offset == 0).then_some(0);
+                    let opened = open_segment(&self.path, self.offset).and_then(|segment| {
+                        let mut segment = BufReader::new(segment);
+                        if self.offset == 0 {
+                            self.offset = read_segment_header(&mut segment)?;
+                        }
+                        Ok(segment)
+                    });
+                    match opened {
+                        Ok(segment) => self.reader.insert(segment),
+                        Err(e) => {
+                            self.error = true;
+                            return Some(Err(self.locate(e)));
+                        }
+                    }
+                }
+            };
 
-            match (transaction.id, self.transaction_id) {
-                (loaded, internal) if loaded < internal => {
+            match self.codec.decode(reader) {
+                Ok(Some((t, length))) => {
+                    if self.segments.len() == 0 {
+                        self.index.record(self.segment, t.id, self.offset);
+                    }
+                    self.offset += length as u64;
+                    self.records = self.records.map(|records| records + 1);
+                    self.last_id = Some(t.id);
+                    // Overshoots within a compressed segment, as the offset is past decompressing
+                    self.replayed
+                        .store(self.passed + self.offset, Ordering::Relaxed);
+                    return Some(Ok(t));
+                }
+                Ok(None) => {
+                    self.passed += fs::metadata(&self.path).map_or(self.offset, |m| m.len());
+                    self.replayed.store(self.passed, Ordering::Relaxed);
+                    self.reader = None;
+                }
+                Err(_) if self.segments.len() == 0 && self.active.is_none() && self.at_end() => {
+                    // Most likely a record the writer is still in the middle of appending, so
+                    // leave it be. It's complete when the log is replayed again.
+                    self.error = true;
+                    return None;
+                }
+                Err(e) if self.segments.len() == 0 && self.at_end() => {
+                    // A crash during `append` leaves a partially written last record behind
                     warn!(
-                        "Skipping recovery of transaction {}, already applied",
-                        transaction.id
+                        segment:? = self.path,
+                        offset = self.offset,
+                        error:% = format!("{:#}", e);
+                        "Truncating torn record at the end of the write-ahead log"
                     );
-                    continue;
-                }
-                (loaded, internal) if loaded > internal => {
-                    return Err(anyhow!(
-                        "Unexpected transaction ID: got {}, expected {}",
-                        transaction.id,
-                        self.transaction_id
-                    ));
+                    self.error = true;
+                    return match self.truncate() {
+                        Ok(()) => None,
+                        Err(e) => Some(Err(e)),
+                    };
                 }
-                _ => {
-                    self.state.apply(&transaction);
-                    self.transaction_id += 1;
-                    info!("Successfully recovered transaction {}", transaction.id);
+                Err(e) => {
+                    self.error = true;
+                    return Some(Err(self.locate(e)));
                 }
             }
         }
-        Ok(())
     }
+}
 
-    fn execute(&mut self, query: &str) -> Result<String> {
-        let command = Self::parse(query);
-        let transaction = Transaction {
-            id: self.transaction_id,
-            command,
-        };
-        self.transaction_id += 1;
-        self.write_ahead_log.append(&transaction)?;
-        let result = self.state.apply(&transaction);
-        Ok(result)
+impl<C: RecordCodec> OnDiskReplayIterator<'_, C> {
+    /// Adds where the iterator is in the log to an error reading it.
+    fn locate(&self, e: anyhow::Error) -> anyhow::Error {
+        let mut location = format!("{} at offset {}", self.path.display(), self.offset);
+        if let Some(records) = self.records {
+            location.push_str(&format!(", record {}", records + 1));
+        }
+        if let Some(id) = self.last_id {
+            location.push_str(&format!(", after transaction {}", id));
+        }
+        e.context(format!(
+            "Failed to read the write-ahead log in {}",
+            location
+        ))
     }
 
-    fn parse(query: &str) -> Command {
-        let parts: Vec<&str> = query.split_whitespace().collect();
-        match parts[..] {
-            ["GET", key] => Command::Get { key: key.into() },
-            ["SET", key, value] => Command::Set {
-                key: key.into(),
-                value: value.into(),
-            },
-            _ => Command::Nop,
+    fn at_end(&mut self) -> bool {
+        self.reader
+            .as_mut()
+            .is_some_and(|r| r.fill_buf().is_ok_and(|b| b.is_empty()))
+    }
+
+    /// Cuts the last segment off after its last valid record, so new appends don't follow the
+    /// garbage.
+    fn truncate(&mut self) -> Result<()> {
+        if let Some(active) = &mut self.active {
+            active.get_ref().set_len(self.offset)?;
+            active.seek(SeekFrom::Start(self.offset))?;
+            self.index.truncate(self.offset);
         }
+        Ok(())
     }
 }
 
-fn main() -> Result<()> {
-    env_logger::Builder::from_default_env()
-        .format_file(true)
-        .format_line_number(true)
-        .format_target(false)
-        .init();
-
-    // In memory
-    // let w = InMemoryWriteAheadLog::new();
+#[cfg(test)]
+impl OnDiskWriteAheadLog {
+    fn new(path: impl AsRef<Path>, durability: Durability) -> Result<Self> {
+        Self::with_codec(path, durability, JsonCodec)
+    }
+}
 
-    // On disk
-    let w = OnDiskWriteAheadLog::new(
-        File::options()
+impl<C: RecordCodec> OnDiskWriteAheadLog<C> {
+    fn with_codec(path: impl AsRef<Path>, durability: Durability, codec: C) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        migrate_legacy_log(&path, &path)?;
+        remove_replaced_segments(&path, &codec)?;
+        // Only sealed segments are compressed, so never append to a compressed one
+        let segment = match segments(&path)?.last() {
+            Some((number, path)) if is_compressed(path) => number + 1,
+            Some((number, _)) => *number,
+            None => 1,
+        };
+        let mut file = File::options()
             .create(true)
             .truncate(false)
             .read(true)
             .write(true)
-            .open("wal.txt")?,
-    );
-
-    let mut s = Server::new(w);
-    s.recover()?;
+            .open(segment_path(&path, segment))?;
+        if file.seek(SeekFrom::End(0))? == 0 {
+            file.write_all(&segment_header())?;
+        }
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        Ok(Self {
+            path,
+            segment,
+            size: file.metadata()?.len(),
+            file: BufWriter::with_capacity(0, file),
+            write_buffer: 0,
+            max_segment_size: None,
+            compress_segments: false,
+            read_only: false,
+            codec,
+            durability,
+            last_sync: clock.now(),
+            synced: true,
+            syncs: 0,
+            clock,
+            index: OffsetIndex::default(),
+            replayed: Arc::default(),
+        })
+    }
 
-    s.execute("SET my_key 3")?;
-    println!("{}", s.execute("GET my_key")?);
+    /// Opens an existing log without ever writing to it, not even to truncate a torn record, so
+    /// it can be followed while another server appends to it.
+    fn open_read_only(path: impl AsRef<Path>, codec: C) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let Some((segment, segment_path)) = segments(&path)?.pop() else {
+            if path.is_file() {
+                return Err(anyhow!(
+                    "{} is a write-ahead log from before segments, start the server on it once to migrate it",
+                    path.display()
+                ));
+            }
+            return Err(anyhow!("No write-ahead log at {}", path.display()));
+        };
+        let file = File::open(&segment_path)?;
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        Ok(Self {
+            path,
+            segment,
+            size: file.metadata()?.len(),
+            file: BufWriter::with_capacity(0, file),
+            write_buffer: 0,
+            max_segment_size: None,
+            compress_segments: false,
+            read_only: true,
+            codec,
+            durability: Durability::Never,
+            last_sync: clock.now(),
+            synced: true,
+            syncs: 0,
+            clock,
+            index: OffsetIndex::default(),
+            replayed: Arc::default(),
+        })
+    }
 
-    s.execute("SET somekey 4")?;
-    println!("{}", s.execute("GET my_key")?);
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(anyhow!(
+                "Write-ahead log {} is read-only",
+                self.path.display()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Uses the given clock to decide when `Durability::EverySecs` syncs are due.
+    #[cfg(test)]
+    fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.last_sync = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// Starts a new segment once the last one has grown to `bytes`. Batches are never split
+    /// across segments, so a segment can end up larger than this.
+    fn with_max_segment_size(mut self, bytes: u64) -> Self {
+        self.max_segment_size = Some(bytes);
+        self
+    }
+
+    /// Buffers up to `bytes` of appended records before writing them to the file, rather than
+    /// writing every batch right away. Syncing, as `Durability` calls for and on `flush`, writes
+    /// the buffer out first, as does every `tick`. Until then, buffered records are lost if the
+    /// process dies, and invisible to replicas following the log.
+    fn with_write_buffer(mut self, bytes: usize) -> Result<Self> {
+        self.file.flush()?;
+        self.write_buffer = bytes;
+        self.file = BufWriter::with_capacity(bytes, self.file.into_inner()?);
+        Ok(self)
+    }
+
+    /// Gzips segments once they're sealed. The last segment is always left uncompressed.
+    fn with_compressed_segments(mut self) -> Self {
+        self.compress_segments = true;
+        self
+    }
+
+    /// Atomically writes the transactions to a new segment, after the existing ones, and appends
+    /// to that from then on.
+    fn write_segment(&mut self, transactions: &[Transaction]) -> Result<()> {
+        let segment = self.segment + 1;
+        let header = segment_header();
+        let (records, offsets) = self.encode(transactions)?;
+        let buffer = [header.as_slice(), &records].concat();
+        let file = write_atomically(&segment_path(&self.path, segment), &buffer)?;
+
+        self.segment = segment;
+        self.file = BufWriter::with_capacity(self.write_buffer, file);
+        self.size = buffer.len() as u64;
+        self.index(header.len() as u64, offsets);
+        self.last_sync = self.clock.now();
+        self.synced = true;
+        Ok(())
+    }
+
+    /// Encodes the transactions, returning the records along with the IDs and offsets of the
+    /// transactions within them.
+    fn encode(&self, transactions: &[Transaction]) -> Result<(Vec<u8>, Offsets)> {
+        let mut buffer = vec![];
+        let mut offsets = vec![];
+        for transaction in transactions {
+            offsets.push((transaction.id, buffer.len() as u64));
+            buffer.extend(self.codec.encode(transaction)?);
+        }
+        Ok((buffer, offsets))
+    }
+
+    fn index(&mut self, start: u64, offsets: Offsets) {
+        for (id, offset) in offsets {
+            self.index.record(self.segment, id, start + offset);
+        }
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        let due = match self.durability {
+            Durability::Always => true,
+            Durability::EverySecs(secs) => {
+                self.clock.now().saturating_sub(self.last_sync) >= secs.saturating_mul(1000)
+            }
+            Durability::Never => false,
+        };
+        if due {
+            self.file.flush()?;
+            self.file.get_ref().sync_data()?;
+            self.syncs += 1;
+            self.last_sync = self.clock.now();
+            self.synced = true;
+        }
+        Ok(())
+    }
+
+    /// Replays the given segments, starting at `start_offset` in the last one. `skipped` is the
+    /// size of the segments before them.
+    fn replay_segments(
+        &mut self,
+        segments: Vec<(u32, PathBuf)>,
+        skipped: u64,
+        start_offset: u64,
+    ) -> OnDiskReplayIterator<'_, C> {
+        self.replayed.store(skipped, Ordering::Relaxed);
+        OnDiskReplayIterator {
+            segments: segments.into_iter(),
+            segment: 0,
+            path: PathBuf::new(),
+            records: None,
+            last_id: None,
+            reader: None,
+            active: (!self.read_only).then_some(&mut self.file),
+            codec: &self.codec,
+            index: &mut self.index,
+            start_offset,
+            offset: 0,
+            passed: skipped,
+            replayed: Arc::clone(&self.replayed),
+            error: false,
+        }
+    }
+
+    /// Reads every record of every segment without applying or truncating anything, checking that
+    /// all of them decode, which includes their checksums if the codec has those, and that the
+    /// transaction IDs count up from 0 without gaps. Reading a segment stops at its first record
+    /// that doesn't decode, as there's no telling where the next one starts.
+    fn verify(&self) -> Result<Verification> {
+        let mut verification = Verification::default();
+        for (_, path) in segments(&self.path)? {
+            verification.segments += 1;
+            let mut reader = BufReader::new(open_segment(&path, 0)?);
+            let mut offset = match read_segment_header(&mut reader) {
+                Ok(size) => size,
+                Err(e) => {
+                    verification.problem(&path, 0, e.to_string());
+                    continue;
+                }
+            };
+            loop {
+                let (transaction, length) = match self.codec.decode(&mut reader) {
+                    Ok(Some(record)) => record,
+                    Ok(None) => break,
+                    Err(e) => {
+                        verification.problem(&path, offset, e.to_string());
+                        break;
+                    }
+                };
+                let expected = verification.last_id.map_or(0, |id| id + 1);
+                if transaction.id != expected {
+                    verification.problem(
+                        &path,
+                        offset,
+                        format!("expected transaction {}, got {}", expected, transaction.id),
+                    );
+                }
+                verification.records += 1;
+                verification.first_id.get_or_insert(transaction.id);
+                verification.last_id = Some(transaction.id);
+                offset += length as u64;
+            }
+        }
+        Ok(verification)
+    }
+
+    /// Seals the last segment and starts appending to a new, empty one.
+    fn rotate(&mut self) -> Result<()> {
+        let path = segment_path(&self.path, self.segment + 1);
+        let mut file = File::options()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let header = segment_header();
+        file.write_all(&header)?;
+        self.file.flush()?;
+        if !matches!(self.durability, Durability::Never) {
+            self.file.get_ref().sync_data()?;
+            self.syncs += 1;
+            sync_parent(&path)?;
+        }
+        let sealed = segment_path(&self.path, self.segment);
+        self.segment += 1;
+        self.file = BufWriter::with_capacity(self.write_buffer, file);
+        self.size = header.len() as u64;
+        info!(segment:? = path; "Rotated write-ahead log");
+
+        if self.compress_segments {
+            compress_segment(&sealed)?;
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of `OnDiskWriteAheadLog::verify`.
+#[derive(Debug, Default)]
+struct Verification {
+    segments: usize,
+    records: usize,
+    first_id: Option<usize>,
+    last_id: Option<usize>,
+    /// Descriptions of what's wrong, including the segment and the byte offset within it.
+    problems: Vec<String>,
+}
+
+impl Verification {
+    fn problem(&mut self, path: &Path, offset: u64, description: String) {
+        self.problems.push(format!(
+            "{} at offset {}: {}",
+            path.display(),
+            offset,
+            description
+        ));
+    }
+}
+
+/// Replaces the segment with a gzipped copy. A crash halfway leaves both behind, in which case
+/// `segments` picks the uncompressed one.
+fn compress_segment(path: &Path) -> Result<()> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    write_atomically(&compressed_path(path), &encoder.finish()?)?;
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Moves a log written before logs were split into segments, a single file at `legacy`, to the
+/// first segment of the log at `path`. Those logs have no segment header, which replay allows
+/// for. Fails if the log at `path` already has segments, rather than pick one of the two.
+fn migrate_legacy_log(legacy: &Path, path: &Path) -> Result<()> {
+    if !legacy.is_file() {
+        return Ok(());
+    }
+    if !segments(path)?.is_empty() {
+        return Err(anyhow!(
+            "Found both a write-ahead log from before segments at {} and segments of {}, remove one of them",
+            legacy.display(),
+            path.display()
+        ));
+    }
+    fs::rename(legacy, segment_path(path, 1))?;
+    warn!(
+        from:% = legacy.display(), to:% = segment_path(path, 1).display();
+        "Moved a write-ahead log from before segments to the first segment"
+    );
+    Ok(())
+}
+
+/// Renames the segment to `<segment>.discarded`, which takes it out of the log without deleting
+/// what it holds.
+fn set_aside_segment(path: &Path, number: u32) -> Result<()> {
+    let path = segment_path(path, number);
+    for path in [compressed_path(&path), path] {
+        if !path.exists() {
+            continue;
+        }
+        let mut aside = path.clone().into_os_string();
+        aside.push(".discarded");
+        let aside = PathBuf::from(aside);
+        if aside.exists() {
+            return Err(anyhow!(
+                "Can't move {} aside, {} already exists",
+                path.display(),
+                aside.display()
+            ));
+        }
+        fs::rename(&path, &aside)?;
+    }
+    Ok(())
+}
+
+/// Removes both copies of the segment, in case a crash during compression left them behind.
+fn remove_segment(path: &Path, number: u32) -> Result<()> {
+    let path = segment_path(path, number);
+    for path in [compressed_path(&path), path] {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// ID of the first transaction in the segment. None if it's empty, or if the first record can't
+/// be read, in which case replaying it reports the error.
+fn first_id(path: &Path, codec: &impl RecordCodec) -> Option<usize> {
+    let mut reader = BufReader::new(open_segment(path, 0).ok()?);
+    read_segment_header(&mut reader).ok()?;
+    match codec.decode(&mut reader) {
+        Ok(Some((transaction, _))) => Some(transaction.id),
+        _ => None,
+    }
+}
+
+/// Starts every segment, followed by the version of the format of the records after it, as a
+/// byte, and a newline. Segments from before there was a header start right at their first
+/// record, and are read as version 1.
+const SEGMENT_MAGIC: &[u8] = b"KVWAL";
+/// Version segments are written in, and the latest one this version of the server reads.
+const SEGMENT_VERSION: u8 = 1;
+
+fn segment_header() -> Vec<u8> {
+    [SEGMENT_MAGIC, &[SEGMENT_VERSION, b'\n']].concat()
+}
+
+/// Reads past the header at the start of a segment, if it has one, returning its size. Fails on
+/// a segment in a format this version of the server doesn't know.
+fn read_segment_header(reader: &mut dyn BufRead) -> Result<u64> {
+    let buffer = reader.fill_buf()?;
+    if !buffer.starts_with(SEGMENT_MAGIC) {
+        return Ok(0);
+    }
+    let size = SEGMENT_MAGIC.len() + 2;
+    match buffer.get(SEGMENT_MAGIC.len()..size) {
+        Some(&[version, b'\n']) if (1..=SEGMENT_VERSION).contains(&version) => {}
+        Some(&[version, b'\n']) => {
+            return Err(anyhow!(
+                "Write-ahead log format version {} is unknown, this server reads up to version {}",
+                version,
+                SEGMENT_VERSION
+            ))
+        }
+        _ => return Err(anyhow!("Invalid write-ahead log segment header")),
+    }
+    reader.consume(size);
+    Ok(size as u64)
+}
+
+/// Opens the segment for reading from `offset` on.
+fn open_segment(path: &Path, offset: u64) -> Result<Box<dyn Read>> {
+    let mut file = File::open(path)?;
+    if is_compressed(path) {
+        let mut decoder = GzDecoder::new(file);
+        io::copy(&mut (&mut decoder).take(offset), &mut io::sink())?;
+        return Ok(Box::new(decoder));
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    Ok(Box::new(file))
+}
+
+fn is_compressed(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "gz")
+}
+
+fn compressed_path(path: &Path) -> PathBuf {
+    let mut path = path.to_path_buf().into_os_string();
+    path.push(".gz");
+    PathBuf::from(path)
+}
+
+fn segment_path(path: &Path, segment: u32) -> PathBuf {
+    let mut path = path.to_path_buf().into_os_string();
+    path.push(format!(".{:05}", segment));
+    PathBuf::from(path)
+}
+
+/// Moves aside the segments a rewrite replaced but crashed before removing, rather than delete
+/// them, as they can hold transactions `discard_from` was keeping. Transaction IDs only go down,
+/// or repeat, where a rewritten segment starts, so those are the segments before the last one
+/// that starts at an ID no higher than the segment before it.
+fn remove_replaced_segments(path: &Path, codec: &impl RecordCodec) -> Result<()> {
+    let segments = segments(path)?;
+    let first_ids: Vec<Option<usize>> = segments
+        .iter()
+        .map(|(_, path)| first_id(path, codec))
+        .collect();
+    let rewritten = (1..segments.len()).rev().find(|&i| {
+        let previous = first_ids[..i].iter().flatten().last();
+        first_ids[i].is_some_and(|id| previous.is_some_and(|&previous| previous >= id))
+    });
+    let Some(rewritten) = rewritten else {
+        return Ok(());
+    };
+    for (number, _) in &segments[..rewritten] {
+        set_aside_segment(path, *number)?;
+    }
+    warn!(
+        segments = rewritten;
+        "Moved aside write-ahead log segments left behind by an interrupted rewrite"
+    );
+    Ok(())
+}
+
+/// Lists the numbers and paths of the existing segments of the log at `path`, in order.
+fn segments(path: &Path) -> Result<Vec<(u32, PathBuf)>> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let prefix = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => format!("{}.", name),
+        None => return Err(anyhow!("Invalid write-ahead log path {}", path.display())),
+    };
+
+    let mut segments = vec![];
+    for entry in fs::read_dir(parent)? {
+        let name = entry?.file_name();
+        let Some(suffix) = name.to_str().and_then(|name| name.strip_prefix(&prefix)) else {
+            continue;
+        };
+        let (number, compressed) = match suffix.strip_suffix(".gz") {
+            Some(number) => (number, true),
+            None => (suffix, false),
+        };
+        if number.len() != 5 || !number.bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        let number = number.parse()?;
+        let path = segment_path(path, number);
+        segments.push((
+            number,
+            if compressed {
+                compressed_path(&path)
+            } else {
+                path
+            },
+        ));
+    }
+    // `x.00001` sorts before `x.00001.gz`, so this keeps the uncompressed copy of a segment
+    segments.sort();
+    segments.dedup_by_key(|(number, _)| *number);
+    Ok(segments)
+}
+
+impl<C: RecordCodec> WriteAheadLog for OnDiskWriteAheadLog<C> {
+    type ReplayIterator<'a>
+        = OnDiskReplayIterator<'a, C>
+    where
+        C: 'a;
+
+    fn append(&mut self, transaction: &Transaction) -> Result<()> {
+        self.append_batch(std::slice::from_ref(transaction))
+    }
+
+    fn append_batch(&mut self, transactions: &[Transaction]) -> Result<()> {
+        self.append_unsynced(transactions)?;
+        self.sync()
+    }
+
+    fn append_unsynced(&mut self, transactions: &[Transaction]) -> Result<()> {
+        self.check_writable()?;
+        if self.max_segment_size.is_some_and(|max| self.size >= max) {
+            self.rotate()?;
+        }
+        let (buffer, offsets) = self.encode(transactions)?;
+        self.file.write_all(&buffer)?;
+        self.index(self.size, offsets);
+        self.size += buffer.len() as u64;
+        self.synced = false;
+        Ok(())
+    }
+
+    /// Writes out the write buffer, and syncs once `Durability` calls for it.
+    fn tick(&mut self) -> Result<()> {
+        if self.read_only || self.synced {
+            return Ok(());
+        }
+        self.file.flush()?;
+        match self.durability {
+            Durability::Never => self.synced = true,
+            _ => self.sync()?,
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        self.syncs += 1;
+        self.last_sync = self.clock.now();
+        self.synced = true;
+        Ok(())
+    }
+
+    /// Writes the transactions to a new segment, syncing once they're all written, then removes
+    /// all older segments. The old segments are never modified, so a crash before the new one is
+    /// renamed into place leaves the log as it was. Should a crash leave the old segments behind
+    /// the new one, opening the log moves them aside, see `remove_replaced_segments`.
+    fn rewrite(&mut self, transactions: &[Transaction]) -> Result<()> {
+        self.check_writable()?;
+        let replaced = segments(&self.path)?;
+        self.write_segment(transactions)?;
+        for (number, _) in replaced {
+            remove_segment(&self.path, number)?;
+        }
+        Ok(())
+    }
+
+    /// Like `rewrite`, but moves the segments it replaces aside rather than deleting them, see
+    /// `set_aside_segment`. A crash halfway is finished by `remove_replaced_segments`.
+    fn discard_from(&mut self, id: usize) -> Result<()> {
+        self.check_writable()?;
+        let kept = self
+            .replay()?
+            .take_while(|result| result.as_ref().map_or(true, |t| t.id < id))
+            .collect::<Result<Vec<_>>>()?;
+        let replaced = segments(&self.path)?;
+        // An empty segment doesn't look like a rewrite to `remove_replaced_segments`, so the old
+        // ones go first. A crash in between then leaves the log as empty as it's meant to end up.
+        if kept.is_empty() {
+            for (number, _) in &replaced {
+                set_aside_segment(&self.path, *number)?;
+            }
+        }
+        self.write_segment(&kept)?;
+        if !kept.is_empty() {
+            for (number, _) in &replaced {
+                set_aside_segment(&self.path, *number)?;
+            }
+        }
+        warn!(
+            transaction_id = id, segments = replaced.len();
+            "Moved aside the write-ahead log segments holding discarded transactions"
+        );
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        let mut size = self.file.buffer().len() as u64;
+        for (_, path) in segments(&self.path)? {
+            size += fs::metadata(path)?.len();
+        }
+        Ok(size)
+    }
+
+    fn replayed_bytes(&self) -> Option<Arc<AtomicU64>> {
+        Some(Arc::clone(&self.replayed))
+    }
+
+    fn syncs(&self) -> Option<u64> {
+        Some(self.syncs)
+    }
+
+    /// Removes the segments before the last one starting at or before transaction `id - 1`, then
+    /// starts a new segment, so the next checkpoint can remove this one.
+    fn checkpoint(&mut self, id: usize) -> Result<()> {
+        self.check_writable()?;
+        let Some(last_needed) = id.checked_sub(1) else {
+            return Ok(());
+        };
+        self.file.flush()?;
+        let segments = segments(&self.path)?;
+        let needed = segments
+            .iter()
+            .rposition(|(_, path)| {
+                first_id(path, &self.codec).is_some_and(|first| first <= last_needed)
+            })
+            .unwrap_or(0);
+        for (number, _) in &segments[..needed] {
+            remove_segment(&self.path, *number)?;
+        }
+        if needed > 0 {
+            info!(segments = needed; "Removed write-ahead log segments before the checkpoint");
+        }
+        if self.size > segment_header().len() as u64 {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// Writes out buffered records first, as the segments are read from their files.
+    fn replay(&mut self) -> Result<Self::ReplayIterator<'_>> {
+        self.file.flush()?;
+        let segments = segments(&self.path)?;
+        Ok(self.replay_segments(segments, 0, 0))
+    }
+
+    /// Skips the segments that only hold transactions before `start_id`, recognizable by the
+    /// segment after them starting at or before it. Within the last segment, the offset index
+    /// is used to skip ahead.
+    fn replay_from(
+        &mut self,
+        start_id: usize,
+    ) -> Result<impl Iterator<Item = Result<Transaction>> + '_> {
+        self.file.flush()?;
+        let mut segments = segments(&self.path)?;
+        let mut first = 0;
+        for (i, (_, path)) in segments.iter().enumerate().rev() {
+            if first_id(path, &self.codec).is_some_and(|id| id <= start_id) {
+                first = i;
+                break;
+            }
+        }
+        let mut skipped = 0;
+        for (_, path) in segments.drain(..first) {
+            skipped += fs::metadata(path)?.len();
+        }
+        let start_offset = segments
+            .last()
+            .map_or(0, |(last, _)| self.index.seek(*last, start_id));
+
+        Ok(self
+            .replay_segments(segments, skipped, start_offset)
+            .filter(move |result| {
+                result
+                    .as_ref()
+                    .map_or(true, |transaction| transaction.id >= start_id)
+            }))
+    }
+}
+
+/// Writes the data next to `path` and renames it into place, so a crash at any point leaves
+/// either the old or the new file intact. Returns the new file, opened for reading and writing.
+fn write_atomically(path: &Path, data: &[u8]) -> Result<File> {
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut file = File::options()
+        .create(true)
+        .truncate(true)
+        .read(true)
+        .write(true)
+        .open(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_data()?;
+    fs::rename(&tmp_path, path)?;
+    sync_parent(path)?;
+    Ok(file)
+}
+
+/// Syncs the directory containing `path`, so a file created or renamed there survives a crash.
+fn sync_parent(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+/// A stored value. Serialized untagged, so a string is a plain JSON string, the same as before
+/// there were other types, a list is a JSON array and a hash a JSON object.
+///
+/// Hash fields are kept ordered, like keys, so HGETALL lists them in a stable order.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Value {
+    Str(String),
+    List(VecDeque<String>),
+    Hash(BTreeMap<String, String>),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+        }
+    }
+
+    /// The string held, or the WRONGTYPE error for any other type.
+    fn as_string(&self) -> Result<&String, Reply> {
+        match self {
+            Value::Str(value) => Ok(value),
+            _ => Err(Reply::wrong_type()),
+        }
+    }
+
+    /// The string parsed as the 64-bit integer the counter commands work on.
+    fn as_integer(&self) -> Result<i64, Reply> {
+        self.as_string()?
+            .parse()
+            .map_err(|_| Reply::Error(NOT_AN_INTEGER.into()))
+    }
+
+    fn as_string_mut(&mut self) -> Result<&mut String, Reply> {
+        match self {
+            Value::Str(value) => Ok(value),
+            _ => Err(Reply::wrong_type()),
+        }
+    }
+
+    /// The list held, or the WRONGTYPE error for any other type.
+    fn as_list(&self) -> Result<&VecDeque<String>, Reply> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(Reply::wrong_type()),
+        }
+    }
+
+    fn as_list_mut(&mut self) -> Result<&mut VecDeque<String>, Reply> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(Reply::wrong_type()),
+        }
+    }
+
+    /// The hash held, or the WRONGTYPE error for any other type.
+    fn as_hash(&self) -> Result<&BTreeMap<String, String>, Reply> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(Reply::wrong_type()),
+        }
+    }
+
+    fn as_hash_mut(&mut self) -> Result<&mut BTreeMap<String, String>, Reply> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(Reply::wrong_type()),
+        }
+    }
+}
+
+impl From<Value> for Reply {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Str(value) => Reply::Value(value),
+            Value::List(list) => Reply::Array(list.into_iter().map(Reply::Value).collect()),
+            Value::Hash(hash) => Reply::Array(
+                hash.into_iter()
+                    .flat_map(|(field, value)| [Reply::Value(field), Reply::Value(value)])
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    value: Value,
+    /// Unix timestamp in milliseconds after which the entry no longer exists.
+    expires_at: Option<u64>,
+    /// Access tick of the last command that touched the entry, see `State::touch`. Reads only
+    /// hold a shared lock on the state, hence the atomic.
+    #[serde(skip)]
+    last_used: AtomicU64,
+    /// Unix timestamp in milliseconds of the last command that touched the entry, 0 if none did
+    /// since it was loaded. Only tracked when enabled, see `State::access_clock`.
+    #[serde(skip)]
+    last_access: AtomicU64,
+    /// Number of the write that last changed the entry, see `State::writes`.
+    #[serde(skip)]
+    version: u64,
+}
+
+impl Entry {
+    fn new(value: Value) -> Self {
+        Self {
+            value,
+            expires_at: None,
+            last_used: AtomicU64::new(0),
+            last_access: AtomicU64::new(0),
+            version: 0,
+        }
+    }
+
+    /// Approximate memory used by the entry: the length of the key plus the lengths of the
+    /// strings in the value.
+    fn size(&self, key: &str) -> usize {
+        let value = match &self.value {
+            Value::Str(value) => value.len(),
+            Value::List(list) => list.iter().map(String::len).sum(),
+            Value::Hash(hash) => hash
+                .iter()
+                .map(|(field, value)| field.len() + value.len())
+                .sum(),
+        };
+        key.len() + value
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    /// Commands that recreate the entry under `key`.
+    fn commands(&self, key: &str) -> Vec<Command> {
+        let key = key.to_string();
+        let mut commands = vec![match &self.value {
+            Value::Str(value) => Command::Set {
+                key: key.clone(),
+                value: value.clone(),
+            },
+            Value::List(list) => Command::RPush {
+                key: key.clone(),
+                values: list.iter().cloned().collect(),
+            },
+            Value::Hash(hash) => Command::HSet {
+                key: key.clone(),
+                pairs: hash.clone().into_iter().collect(),
+            },
+        }];
+        if let Some(at) = self.expires_at {
+            commands.push(Command::ExpireAt { key, at });
+        }
+        commands
+    }
+}
+
+/// Expired entries are removed lazily. Reads ignore them, and before a mutation touches an
+/// expired key the server logs a delete for it, so `apply` itself never depends on the clock and
+/// replaying the write-ahead log always reproduces the same state.
+///
+/// Keys are kept ordered so they can be iterated over in a stable order across calls.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Database {
+    kv: BTreeMap<String, Entry>,
+    /// The keys that expire, ordered by when, so the expired ones can be found without a scan.
+    #[serde(skip)]
+    expiries: BTreeSet<(u64, String)>,
+    /// The entries UNLINK removed, until they're taken to be reclaimed.
+    #[serde(skip)]
+    unlinked: Vec<(String, Entry)>,
+}
+
+impl Database {
+    fn apply(&mut self, command: &Command) -> Reply {
+        match command {
+            Command::Set { key, value } => {
+                self.kv
+                    .insert(key.into(), Entry::new(Value::Str(value.clone())));
+                Reply::Ok
+            }
+            Command::SetExAt { key, value, at } => {
+                let entry = Entry {
+                    expires_at: Some(*at),
+                    ..Entry::new(Value::Str(value.clone()))
+                };
+                self.kv.insert(key.into(), entry);
+                Reply::Ok
+            }
+            Command::SetNx { key, value } => {
+                if self.kv.contains_key(key) {
+                    return Reply::Int(0);
+                }
+                self.kv
+                    .insert(key.into(), Entry::new(Value::Str(value.clone())));
+                Reply::Int(1)
+            }
+            // Only the value is swapped, the key keeps its TTL
+            Command::Cas { key, expected, new } => {
+                match self.kv.get_mut(key).map(|e| e.value.as_string_mut()) {
+                    Some(Ok(value)) if value == expected => {
+                        *value = new.clone();
+                        Reply::Int(1)
+                    }
+                    Some(Err(reply)) => reply,
+                    _ => Reply::Int(0),
+                }
+            }
+            Command::GetSet { key, value } => {
+                if let Some(Err(reply)) = self.kv.get(key).map(|e| e.value.as_string()) {
+                    return reply;
+                }
+                self.kv
+                    .insert(key.into(), Entry::new(Value::Str(value.clone())))
+                    .map_or(Reply::Nil, |e| e.value.into())
+            }
+            Command::Append { key, value } => {
+                let entry = self
+                    .kv
+                    .entry(key.into())
+                    .or_insert_with(|| Entry::new(Value::Str(String::new())));
+                match entry.value.as_string_mut() {
+                    Ok(string) => {
+                        string.push_str(value);
+                        Reply::Int(string.len() as i64)
+                    }
+                    Err(reply) => reply,
+                }
+            }
+            // Values are strings, so overwriting part of a multibyte character is refused
+            Command::SetRange { key, offset, value } => {
+                if value.is_empty() {
+                    return match self.kv.get(key).map(|e| e.value.as_string()) {
+                        Some(Ok(string)) => Reply::Int(string.len() as i64),
+                        Some(Err(reply)) => reply,
+                        None => Reply::Int(0),
+                    };
+                }
+                // Checked again as the log may hold records from before the parser checked
+                let Some(end) = offset
+                    .checked_add(value.len())
+                    .filter(|end| *end <= MAX_STRING_LEN)
+                else {
+                    return Reply::Error(STRING_TOO_LONG.into());
+                };
+                let entry = self
+                    .kv
+                    .entry(key.into())
+                    .or_insert_with(|| Entry::new(Value::Str(String::new())));
+                match entry.value.as_string_mut() {
+                    Ok(string) => {
+                        if *offset > string.len() {
+                            string.extend(std::iter::repeat_n('\0', offset - string.len()));
+                        }
+                        if !string.is_char_boundary(*offset)
+                            || !string.is_char_boundary(end.min(string.len()))
+                        {
+                            return Reply::Error(
+                                "ERR SETRANGE would overwrite part of a multibyte character".into(),
+                            );
+                        }
+                        string.replace_range(*offset..end.min(string.len()), value);
+                        Reply::Int(string.len() as i64)
+                    }
+                    Err(reply) => reply,
+                }
+            }
+            Command::MSet { pairs } => {
+                for (key, value) in pairs {
+                    self.kv
+                        .insert(key.into(), Entry::new(Value::Str(value.clone())));
+                }
+                Reply::Ok
+            }
+            Command::LPush { key, values } | Command::RPush { key, values } => {
+                let entry = self
+                    .kv
+                    .entry(key.into())
+                    .or_insert_with(|| Entry::new(Value::List(VecDeque::new())));
+                match entry.value.as_list_mut() {
+                    Ok(list) => {
+                        for value in values {
+                            if matches!(command, Command::LPush { .. }) {
+                                list.push_front(value.clone());
+                            } else {
+                                list.push_back(value.clone());
+                            }
+                        }
+                        Reply::Int(list.len() as i64)
+                    }
+                    Err(reply) => reply,
+                }
+            }
+            // Replies with the number of fields that were added rather than updated
+            Command::HSet { key, pairs } => {
+                let entry = self
+                    .kv
+                    .entry(key.into())
+                    .or_insert_with(|| Entry::new(Value::Hash(BTreeMap::new())));
+                match entry.value.as_hash_mut() {
+                    Ok(hash) => {
+                        let added = pairs
+                            .iter()
+                            .filter(|(field, value)| {
+                                hash.insert(field.clone(), value.clone()).is_none()
+                            })
+                            .count();
+                        Reply::Int(added as i64)
+                    }
+                    Err(reply) => reply,
+                }
+            }
+            Command::Delete { key } => self.kv.remove(key).map_or(Reply::Nil, |e| e.value.into()),
+            Command::Unlink { keys } => {
+                let before = self.unlinked.len();
+                for key in keys {
+                    self.unlinked.extend(self.kv.remove_entry(key));
+                }
+                Reply::Int((self.unlinked.len() - before) as i64)
+            }
+            Command::GetDel { key } => {
+                if let Some(Err(reply)) = self.kv.get(key).map(|e| e.value.as_string()) {
+                    return reply;
+                }
+                self.kv.remove(key).map_or(Reply::Nil, |e| e.value.into())
+            }
+            Command::Rename { src, dst } => match self.kv.remove(src) {
+                Some(entry) => {
+                    self.kv.insert(dst.into(), entry);
+                    Reply::Ok
+                }
+                None => Reply::Error("ERR no such key".into()),
+            },
+            // Copies the expiry along with the value. Replies 0 if `dst` exists and isn't replaced.
+            Command::Copy { src, dst, replace } => {
+                let Some(entry) = self.kv.get(src) else {
+                    return Reply::Error("ERR no such key".into());
+                };
+                if !replace && self.kv.contains_key(dst) {
+                    return Reply::Int(0);
+                }
+                let copy = Entry {
+                    expires_at: entry.expires_at,
+                    ..Entry::new(entry.value.clone())
+                };
+                self.kv.insert(dst.into(), copy);
+                Reply::Int(1)
+            }
+            Command::Incr { key, delta } => {
+                let current = match self.kv.get(key).map(|e| e.value.as_integer()) {
+                    Some(Ok(current)) => current,
+                    Some(Err(reply)) => return reply,
+                    None => 0,
+                };
+                match current.checked_add(*delta) {
+                    Some(new) => {
+                        let entry = self
+                            .kv
+                            .entry(key.into())
+                            .or_insert_with(|| Entry::new(Value::Str(String::new())));
+                        entry.value = Value::Str(new.to_string());
+                        Reply::Int(new)
+                    }
+                    None => Reply::Error(OVERFLOW.into()),
+                }
+            }
+            Command::ExpireAt { key, at } => match self.kv.get_mut(key) {
+                Some(entry) => {
+                    entry.expires_at = Some(*at);
+                    Reply::Int(1)
+                }
+                None => Reply::Int(0),
+            },
+            Command::Persist { key } => match self.kv.get_mut(key) {
+                Some(entry) if entry.expires_at.is_some() => {
+                    entry.expires_at = None;
+                    Reply::Int(1)
+                }
+                _ => Reply::Int(0),
+            },
+            Command::GetEx { key, ttl } => {
+                let Some(entry) = self.kv.get_mut(key) else {
+                    return Reply::Nil;
+                };
+                let value = match entry.value.as_string() {
+                    Ok(value) => value.clone(),
+                    Err(reply) => return reply,
+                };
+                match ttl {
+                    Some(ExpiryOpt::At(at)) => entry.expires_at = Some(*at),
+                    Some(ExpiryOpt::Persist) => entry.expires_at = None,
+                    _ => {}
+                }
+                Reply::Value(value)
+            }
+            // Listed rather than matched with `_`, so a new command can't be left out by
+            // accident. `SetEx` and `Expire` are resolved into `SetExAt` and `ExpireAt` before
+            // they're applied, and `State::apply` applies `FlushAll` and `Batch` itself.
+            Command::SetEx { .. }
+            | Command::Expire { .. }
+            | Command::FlushAll
+            | Command::Batch { .. } => Reply::Nil,
+            // Read-only, so only found in logs written before reads stopped being logged
+            Command::Get { .. }
+            | Command::Exists { .. }
+            | Command::StrLen { .. }
+            | Command::GetRange { .. }
+            | Command::Type { .. }
+            | Command::Ttl { .. }
+            | Command::Keys { .. }
+            | Command::Scan { .. }
+            | Command::MGet { .. }
+            | Command::Touch { .. }
+            | Command::ObjectIdleTime { .. }
+            | Command::LRange { .. }
+            | Command::HGet { .. }
+            | Command::HGetAll { .. }
+            | Command::DbSize
+            | Command::RandomKey
+            | Command::Select { .. }
+            | Command::Info
+            | Command::Dump
+            | Command::Restore { .. }
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch { .. }
+            | Command::Unwatch
+            | Command::Subscribe { .. }
+            | Command::Unsubscribe { .. }
+            | Command::Publish { .. }
+            | Command::Ping { .. }
+            | Command::Echo { .. }
+            | Command::LastId
+            | Command::ListCommands
+            | Command::ExpireSweep
+            | Command::Nop => Reply::Nil,
+        }
+    }
+
+    fn query(&self, command: &Command, now: u64) -> Reply {
+        match command {
+            Command::Get { key } | Command::GetEx { key, .. } => match self.get_string(key, now) {
+                Ok(value) => value.cloned().into(),
+                Err(reply) => reply,
+            },
+            // Like Redis, keys holding another type than a string are nil here rather than errors
+            Command::MGet { keys } => Reply::Array(
+                keys.iter()
+                    .map(|key| self.get_string(key, now).ok().flatten().cloned().into())
+                    .collect(),
+            ),
+            Command::Exists { keys } | Command::Touch { keys } => Reply::Int(
+                keys.iter()
+                    .filter(|key| self.get(key, now).is_some())
+                    .count() as i64,
+            ),
+            // Remaining time to live in seconds, -1 if the key doesn't expire, -2 if it's missing
+            Command::Ttl { key } => match self.get(key, now) {
+                Some(Entry {
+                    expires_at: Some(at),
+                    ..
+                }) => Reply::Int(((at - now + 500) / 1000) as i64),
+                Some(_) => Reply::Int(-1),
+                None => Reply::Int(-2),
+            },
+            // Scans the entire keyspace, so this is O(n) in the number of keys
+            Command::Keys { pattern } => Reply::Array(
+                self.kv
+                    .iter()
+                    .filter(|(key, entry)| !entry.is_expired(now) && glob_match(pattern, key))
+                    .map(|(key, _)| Reply::Value(key.clone()))
+                    .collect(),
+            ),
+            // The cursor is the position in the ordered keyspace to continue from. Inserts or
+            // deletes of keys ordered before the cursor shift it, so a key can then be returned
+            // twice or skipped.
+            Command::Scan { cursor, count } => {
+                let mut batch = self.kv.iter().skip(*cursor);
+                let keys = batch
+                    .by_ref()
+                    .take(*count)
+                    .filter(|(_, entry)| !entry.is_expired(now))
+                    .map(|(key, _)| Reply::Value(key.clone()))
+                    .collect();
+                let next = if batch.next().is_some() {
+                    cursor + count
+                } else {
+                    0
+                };
+                Reply::Array(vec![Reply::Value(next.to_string()), Reply::Array(keys)])
+            }
+            Command::StrLen { key } => match self.get_string(key, now) {
+                Ok(value) => Reply::Int(value.map_or(0, |value| value.len()) as i64),
+                Err(reply) => reply,
+            },
+            // A range that starts or ends halfway through a multibyte character has the partial
+            // character replaced by U+FFFD, as replies are strings
+            Command::GetRange { key, start, end } => match self.get_string(key, now) {
+                Ok(value) => {
+                    let value = value.map_or(&[][..], |value| value.as_bytes());
+                    let range = list_range(value.len(), *start, *end);
+                    Reply::Value(String::from_utf8_lossy(&value[range]).into_owned())
+                }
+                Err(reply) => reply,
+            },
+            Command::LRange { key, start, stop } => {
+                let list = match self.get(key, now).map(|e| e.value.as_list()) {
+                    Some(Ok(list)) => list,
+                    Some(Err(reply)) => return reply,
+                    None => return Reply::Array(vec![]),
+                };
+                Reply::Array(
+                    list_range(list.len(), *start, *stop)
+                        .map(|i| Reply::Value(list[i].clone()))
+                        .collect(),
+                )
+            }
+            Command::HGet { key, field } => match self.get(key, now).map(|e| e.value.as_hash()) {
+                Some(Ok(hash)) => hash.get(field).cloned().into(),
+                Some(Err(reply)) => reply,
+                None => Reply::Nil,
+            },
+            Command::HGetAll { key } => match self.get(key, now) {
+                Some(Entry {
+                    value: value @ Value::Hash(_),
+                    ..
+                }) => value.clone().into(),
+                Some(_) => Reply::wrong_type(),
+                None => Reply::Array(vec![]),
+            },
+            Command::Type { key } => Reply::Value(
+                self.get(key, now)
+                    .map_or("none", |e| e.value.type_name())
+                    .into(),
+            ),
+            // Like Redis, this counts expired keys that haven't been removed yet
+            Command::DbSize => Reply::Int(self.kv.len() as i64),
+            Command::Ping { message } => message.clone().map_or(Reply::Pong, Reply::Value),
+            Command::Echo { message } => Reply::Value(message.clone()),
+            Command::ListCommands => {
+                Reply::Array(COMMANDS.iter().map(CommandSpec::reply).collect())
+            }
+            _ => Reply::Nil,
+        }
+    }
+
+    /// Returns the reply to a mutating command that wouldn't change anything, so it can be
+    /// answered without going through the write-ahead log.
+    fn without_effect(&self, command: &Command, now: u64) -> Option<Reply> {
+        match command {
+            Command::SetNx { key, .. } if self.get(key, now).is_some() => Some(Reply::Int(0)),
+            // A failed swap is not logged at all
+            Command::Cas { key, expected, .. } => match self.get_string(key, now) {
+                Ok(Some(value)) if value == expected => None,
+                Ok(_) => Some(Reply::Int(0)),
+                Err(reply) => Some(reply),
+            },
+            Command::Rename { src, .. } | Command::Copy { src, .. }
+                if self.get(src, now).is_none() =>
+            {
+                Some(Reply::Error("ERR no such key".into()))
+            }
+            Command::Copy {
+                dst,
+                replace: false,
+                ..
+            } if self.get(dst, now).is_some() => Some(Reply::Int(0)),
+            Command::LPush { key, .. } | Command::RPush { key, .. }
+                if self
+                    .get(key, now)
+                    .is_some_and(|e| e.value.as_list().is_err()) =>
+            {
+                Some(Reply::wrong_type())
+            }
+            Command::HSet { key, .. }
+                if self
+                    .get(key, now)
+                    .is_some_and(|e| e.value.as_hash().is_err()) =>
+            {
+                Some(Reply::wrong_type())
+            }
+            // Like Redis, an empty value doesn't create the key nor pad the value
+            Command::SetRange { key, value, .. } if value.is_empty() => {
+                match self.get_string(key, now) {
+                    Ok(string) => Some(Reply::Int(string.map_or(0, |s| s.len()) as i64)),
+                    Err(reply) => Some(reply),
+                }
+            }
+            // Neither is a failed increment
+            Command::Incr { key, delta } => {
+                match self.get(key, now).map(|e| e.value.as_integer()) {
+                    Some(Err(reply)) => Some(reply),
+                    Some(Ok(current)) if current.checked_add(*delta).is_none() => {
+                        Some(Reply::Error(OVERFLOW.into()))
+                    }
+                    _ => None,
+                }
+            }
+            // Removing a missing key is not logged at all
+            Command::GetDel { key } if self.get(key, now).is_none() => Some(Reply::Nil),
+            // Neither is clearing the expiry of a key that doesn't have one
+            Command::GetEx { key, ttl } => match self.get(key, now) {
+                None => Some(Reply::Nil),
+                Some(entry) => match entry.value.as_string() {
+                    Ok(value) if *ttl == Some(ExpiryOpt::Persist) && entry.expires_at.is_none() => {
+                        Some(Reply::Value(value.clone()))
+                    }
+                    Ok(_) => None,
+                    Err(reply) => Some(reply),
+                },
+            },
+            Command::Unlink { keys } if keys.iter().all(|key| self.get(key, now).is_none()) => {
+                Some(Reply::Int(0))
+            }
+            Command::Persist { key }
+                if self
+                    .get(key, now)
+                    .is_none_or(|entry| entry.expires_at.is_none()) =>
+            {
+                Some(Reply::Int(0))
+            }
+            _ => None,
+        }
+    }
+
+    /// The size of the entry at `key` if `sizes` is set, and when it expires.
+    fn footprint(&self, key: &str, sizes: bool) -> (usize, Option<u64>) {
+        match self.kv.get(key) {
+            Some(entry) => (if sizes { entry.size(key) } else { 0 }, entry.expires_at),
+            None => (0, None),
+        }
+    }
+
+    /// Moves `key` in `expiries` from when it used to expire to when it expires now.
+    fn reindex(&mut self, key: &str, old: Option<u64>, new: Option<u64>) {
+        if old == new {
+            return;
+        }
+        if let Some(at) = old {
+            self.expiries.remove(&(at, key.to_string()));
+        }
+        if let Some(at) = new {
+            self.expiries.insert((at, key.to_string()));
+        }
+    }
+
+    fn last_used(&self, key: &str) -> Option<u64> {
+        let entry = self.kv.get(key)?;
+        Some(entry.last_used.load(Ordering::Relaxed))
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.kv.remove(key)?;
+        self.reindex(key, entry.expires_at, None);
+        Some(entry)
+    }
+
+    /// Keys that expired by `now`, the longest expired first.
+    fn expiring(&self, now: u64) -> impl Iterator<Item = &String> {
+        self.expiries
+            .iter()
+            .take_while(move |(at, _)| *at <= now)
+            .map(|(_, key)| key)
+    }
+
+    /// Version of the entry at `key`, or `None` if there's none.
+    fn version(&self, key: &str) -> Option<u64> {
+        Some(self.kv.get(key)?.version)
+    }
+
+    fn get(&self, key: &str, now: u64) -> Option<&Entry> {
+        self.kv.get(key).filter(|e| !e.is_expired(now))
+    }
+
+    fn get_string(&self, key: &str, now: u64) -> Result<Option<&String>, Reply> {
+        self.get(key, now).map(|e| e.value.as_string()).transpose()
+    }
+
+    /// Keys touched by the command that have expired but are still stored.
+    fn expired<'a>(&self, command: &'a Command, now: u64) -> Vec<&'a str> {
+        command
+            .keys()
+            .into_iter()
+            .filter(|key| self.kv.get(*key).is_some_and(|e| e.is_expired(now)))
+            .collect()
+    }
+}
+
+/// Limits on the size of keys and values, enforced by rejecting the commands writing larger ones.
+/// `None` and 0 both mean unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct MaxSizes {
+    key_bytes: Option<usize>,
+    value_bytes: Option<usize>,
+}
+
+/// Number of databases that can be selected with SELECT.
+const DATABASES: usize = 16;
+
+/// Limits on the size of the state, enforced by evicting the least recently used keys.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Limits {
+    /// Number of keys across all databases.
+    max_keys: Option<usize>,
+    /// Approximate number of bytes across all databases, see `Entry::size`.
+    max_bytes: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    databases: Vec<Database>,
+    #[serde(skip)]
+    limits: Limits,
+    /// Sum of `Entry::size` over all keys. Only kept up to date when there's a byte limit.
+    #[serde(skip)]
+    bytes: usize,
+    /// Source of the access ticks in `Entry::last_used`.
+    #[serde(skip)]
+    ticks: AtomicU64,
+    /// Every key by `Entry::last_used`, then database and key, so `evict` finds the least
+    /// recently used ones without a scan. Only kept when there are limits. Reads touch keys while
+    /// holding a shared lock on the state, hence the mutex.
+    #[serde(skip)]
+    lru: Mutex<BTreeSet<(u64, usize, String)>>,
+    /// Number of mutating commands applied since startup, which versions the entries they touch.
+    #[serde(skip)]
+    writes: u64,
+    /// Timestamps `Entry::last_access`, if idle times are tracked.
+    #[serde(skip)]
+    access_clock: Option<Arc<dyn Clock>>,
+    /// When idle times started being tracked, which entries that weren't touched since count as
+    /// their last access.
+    #[serde(skip)]
+    tracked_since: u64,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            databases: (0..DATABASES).map(|_| Database::default()).collect(),
+            limits: Limits::default(),
+            bytes: 0,
+            ticks: AtomicU64::new(0),
+            lru: Mutex::default(),
+            writes: 0,
+            access_clock: None,
+            tracked_since: 0,
+        }
+    }
+
+    /// Takes the entries UNLINK removed from all databases.
+    fn take_unlinked(&mut self) -> Vec<(String, Entry)> {
+        self.databases
+            .iter_mut()
+            .flat_map(|database| std::mem::take(&mut database.unlinked))
+            .collect()
+    }
+
+    fn apply(&mut self, db: usize, command: &Command) -> Reply {
+        let reply = match command {
+            Command::Batch { commands } => {
+                return Reply::Array(
+                    commands
+                        .iter()
+                        .map(|(db, command)| self.apply(*db, command))
+                        .collect(),
+                )
+            }
+            Command::FlushAll => {
+                for database in &mut self.databases {
+                    database.kv.clear();
+                    database.expiries.clear();
+                }
+                self.bytes = 0;
+                self.lru.get_mut().unwrap().clear();
+                Reply::Ok
+            }
+            _ => {
+                // A key can appear more than once, as in `MSET a 1 a 2`
+                let mut keys = command.keys();
+                keys.sort_unstable();
+                keys.dedup();
+                let sizes = self.limits.max_bytes.is_some();
+                let ordered = self.limits != Limits::default();
+                let database = &mut self.databases[db];
+                let lru = self.lru.get_mut().unwrap();
+                let before: Vec<_> = keys
+                    .iter()
+                    .map(|key| (database.footprint(key, sizes), database.last_used(key)))
+                    .collect();
+                let reply = database.apply(command);
+                for (key, ((size, expires_at), last_used)) in keys.iter().zip(before) {
+                    let (new_size, new_expires_at) = database.footprint(key, sizes);
+                    self.bytes = self.bytes - size + new_size;
+                    database.reindex(key, expires_at, new_expires_at);
+                    if ordered {
+                        // The entry can be a new one, or another key's after a RENAME
+                        if let Some(last_used) = last_used {
+                            lru.remove(&(last_used, db, key.to_string()));
+                        }
+                        if let Some(last_used) = database.last_used(key) {
+                            lru.insert((last_used, db, key.to_string()));
+                        }
+                    }
+                }
+                reply
+            }
+        };
+        // Also bumps the version of keys the command left unchanged, like SETNX on an existing key,
+        // which at worst fails an EXEC that could have gone through
+        self.writes += 1;
+        for key in command.keys() {
+            if let Some(entry) = self.databases[db].kv.get_mut(key) {
+                entry.version = self.writes;
+            }
+        }
+        self.touch(db, command);
+        reply
+    }
+
+    fn query(&self, db: usize, command: &Command, now: u64) -> Reply {
+        if let Command::ObjectIdleTime { key } = command {
+            return self.idle_time(db, key, now);
+        }
+        let reply = self.databases[db].query(command, now);
+        self.touch(db, command);
+        reply
+    }
+
+    /// Marks the keys of `command` as most recently used. Only tracked when there are limits, and
+    /// timestamped when idle times are tracked.
+    fn touch(&self, db: usize, command: &Command) {
+        let tick = (self.limits != Limits::default())
+            .then(|| self.ticks.fetch_add(1, Ordering::Relaxed) + 1);
+        let now = self.access_clock.as_ref().map(|clock| clock.now());
+        if tick.is_none() && now.is_none() {
+            return;
+        }
+        let mut lru = tick.map(|_| self.lru.lock().unwrap());
+        for key in command.keys() {
+            if let Some(entry) = self.databases[db].kv.get(key) {
+                if let (Some(tick), Some(lru)) = (tick, &mut lru) {
+                    // Swapped while holding the lock, so concurrent reads of the key can't leave
+                    // it in `lru` twice
+                    let last_used = entry.last_used.swap(tick, Ordering::Relaxed);
+                    lru.remove(&(last_used, db, key.to_string()));
+                    lru.insert((tick, db, key.to_string()));
+                }
+                if let Some(now) = now {
+                    entry.last_access.store(now, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Starts timestamping accesses with `clock`, so OBJECT IDLETIME can report them. Costs a
+    /// clock read per command.
+    fn track_idle_times(&mut self, clock: Arc<dyn Clock>) {
+        self.tracked_since = clock.now();
+        self.access_clock = Some(clock);
+    }
+
+    /// Seconds since the entry at `key` was last touched, or since tracking started if it wasn't.
+    fn idle_time(&self, db: usize, key: &str, now: u64) -> Reply {
+        if self.access_clock.is_none() {
+            return Reply::Error("ERR idle times aren't tracked".into());
+        }
+        match self.databases[db].get(key, now) {
+            Some(entry) => {
+                let last_access = entry.last_access.load(Ordering::Relaxed);
+                let since = last_access.max(self.tracked_since);
+                Reply::Int((now.saturating_sub(since) / 1000) as i64)
+            }
+            None => Reply::Nil,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.databases.iter().map(|db| db.kv.len()).sum()
+    }
+
+    /// Sets the limits and rebuilds what's kept track of alongside the entries, as it isn't part
+    /// of snapshots and the byte total and LRU order aren't kept without limits.
+    fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.bytes = 0;
+        let lru = self.lru.get_mut().unwrap();
+        lru.clear();
+        for (db, database) in self.databases.iter_mut().enumerate() {
+            if limits != Limits::default() {
+                lru.extend(database.kv.iter().map(|(key, entry)| {
+                    (entry.last_used.load(Ordering::Relaxed), db, key.clone())
+                }));
+            }
+            database.expiries = database
+                .kv
+                .iter()
+                .filter_map(|(key, entry)| Some((entry.expires_at?, key.clone())))
+                .collect();
+            self.bytes += database
+                .kv
+                .iter()
+                .map(|(key, entry)| entry.size(key))
+                .sum::<usize>();
+        }
+    }
+
+    fn over_limits(&self, keys: usize, bytes: usize) -> bool {
+        self.limits.max_keys.is_some_and(|max| keys > max)
+            || self.limits.max_bytes.is_some_and(|max| bytes > max)
+    }
+
+    /// Removes keys until the state is within its limits, and returns how many were removed.
+    /// Expired keys go first, the longest expired first, then the least recently used ones. Keys
+    /// that were never touched since loading a snapshot count as least recently used, ties are
+    /// broken by database and key order. A value larger than the byte limit by itself is evicted
+    /// right away.
+    ///
+    /// Evictions aren't logged, so recovery brings evicted keys back until it's done and this
+    /// runs again. What's evicted then follows the order the keys were written in, as reads
+    /// aren't logged either.
+    fn evict(&mut self, now: u64) -> usize {
+        let mut keys = self.len();
+        let mut evicted = 0;
+        while self.over_limits(keys, self.bytes) {
+            let expired = (0..self.databases.len())
+                .filter_map(|db| {
+                    let (at, key) = self.databases[db].expiries.first()?;
+                    (*at <= now).then(|| (*at, db, key.clone()))
+                })
+                .min();
+            let (db, key) = match expired {
+                Some((_, db, key)) => (db, key),
+                None => match self.lru.get_mut().unwrap().pop_first() {
+                    Some((_, db, key)) => (db, key),
+                    None => break,
+                },
+            };
+            let Some(entry) = self.databases[db].remove(&key) else {
+                continue;
+            };
+            let last_used = entry.last_used.load(Ordering::Relaxed);
+            self.lru
+                .get_mut()
+                .unwrap()
+                .remove(&(last_used, db, key.clone()));
+            keys -= 1;
+            // Without a byte limit the total isn't kept
+            self.bytes = self.bytes.saturating_sub(entry.size(&key));
+            evicted += 1;
+            debug!(key = key.as_str(), db = db; "Evicted key");
+        }
+        evicted
+    }
+}
+
+/// Reply to a command, kept structured until it's encoded for the protocol the client speaks.
+#[derive(Clone, PartialEq, Debug)]
+enum Reply {
+    Ok,
+    /// A command was queued by MULTI
+    Queued,
+    /// PING without a message
+    Pong,
+    Nil,
+    Value(String),
+    Int(i64),
+    Error(String),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    /// The error for a command on a key holding another type than the one it works on, the same
+    /// for every type.
+    fn wrong_type() -> Self {
+        Reply::Error(WRONGTYPE.into())
+    }
+}
+
+impl From<Option<String>> for Reply {
+    fn from(value: Option<String>) -> Self {
+        value.map_or(Reply::Nil, Reply::Value)
+    }
+}
+
+/// How far `Server::recover_until` replays the write-ahead log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RecoverTarget {
+    End,
+    /// Up to and including the transaction with this ID
+    Id(usize),
+    /// Up to the first transaction logged after this Unix timestamp in milliseconds
+    Timestamp(u64),
+}
+
+impl RecoverTarget {
+    /// Whether recovery can start from a snapshot holding the transactions before
+    /// `transaction_id`, rather than hold some past the target. Snapshots don't record when their
+    /// transactions were logged, so only an empty one can precede a timestamp.
+    fn admits(&self, transaction_id: usize) -> bool {
+        match self {
+            RecoverTarget::End => true,
+            RecoverTarget::Id(id) => transaction_id <= id + 1,
+            RecoverTarget::Timestamp(_) => transaction_id == 0,
+        }
+    }
+
+    fn includes(&self, transaction: &Transaction) -> bool {
+        match self {
+            RecoverTarget::End => true,
+            RecoverTarget::Id(id) => transaction.id <= *id,
+            RecoverTarget::Timestamp(timestamp) => transaction.timestamp <= *timestamp,
+        }
+    }
+}
+
+/// When `Server::spawn_checkpointer` checkpoints: after as many transactions or as much time,
+/// whichever comes first.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct CheckpointPolicy {
+    every_transactions: Option<usize>,
+    every_ms: Option<u64>,
+}
+
+impl CheckpointPolicy {
+    fn is_due(&self, transactions: usize, elapsed_ms: u64) -> bool {
+        transactions > 0
+            && (self.every_transactions.is_some_and(|n| transactions >= n)
+                || self.every_ms.is_some_and(|ms| elapsed_ms >= ms))
+    }
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    transaction_id: usize,
+    state: State,
+}
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    transaction_id: usize,
+    state: &'a State,
+}
+
+/// Per connection state.
+#[derive(Debug, Default)]
+struct Session {
+    db: usize,
+    /// Commands queued since MULTI, or `None` outside of a transaction.
+    queued: Option<Vec<Command>>,
+    /// Whether a command failed to queue, in which case EXEC discards the transaction.
+    aborted: bool,
+    /// Keys watched since WATCH, with the database they're in and their version at the time.
+    watched: Vec<(usize, String, Option<u64>)>,
+    /// Set by the first SUBSCRIBE.
+    subscriber: Option<Subscriber>,
+}
+
+impl Session {
+    /// Makes the transaction in progress, if any, fail on EXEC.
+    fn abort(&mut self) {
+        if self.queued.is_some() {
+            self.aborted = true;
+        }
+    }
+
+    /// Whether the session is subscribed to any channel, which limits it to (UN)SUBSCRIBE.
+    fn is_subscribed(&self) -> bool {
+        self.subscriber
+            .as_ref()
+            .is_some_and(|subscriber| !subscriber.channels.is_empty())
+    }
+
+    /// The messages published to the channels the session subscribes to, the first time this is
+    /// called after subscribing. The connection forwards them to the client.
+    fn take_messages(&mut self) -> Option<Receiver<Message>> {
+        self.subscriber.as_mut()?.receiver.take()
+    }
+
+    /// Leaves the transaction in progress, returning the queued commands, and stops watching.
+    fn reset(&mut self) -> Option<Vec<Command>> {
+        self.aborted = false;
+        self.watched.clear();
+        self.queued.take()
+    }
+}
+
+/// A message published to a channel.
+#[derive(Clone, PartialEq, Debug)]
+struct Message {
+    channel: String,
+    message: String,
+}
+
+impl From<Message> for Reply {
+    fn from(message: Message) -> Self {
+        Reply::Array(vec![
+            Reply::Value("message".into()),
+            Reply::Value(message.channel),
+            Reply::Value(message.message),
+        ])
+    }
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    id: u64,
+    sender: Sender<Message>,
+    receiver: Option<Receiver<Message>>,
+    channels: BTreeSet<String>,
+}
+
+/// Routes published messages to the sessions subscribed to their channel. Messages are only
+/// delivered to current subscribers, and never logged.
+#[derive(Debug, Default)]
+struct PubSub {
+    subscribers: AtomicU64,
+    channels: Mutex<BTreeMap<String, BTreeMap<u64, Sender<Message>>>>,
+}
+
+impl PubSub {
+    fn subscribe(&self, session: &mut Session, channel: String) -> Reply {
+        let subscriber = session.subscriber.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel();
+            Subscriber {
+                id: self.subscribers.fetch_add(1, Ordering::Relaxed),
+                sender,
+                receiver: Some(receiver),
+                channels: BTreeSet::new(),
+            }
+        });
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(channel.clone())
+            .or_default()
+            .insert(subscriber.id, subscriber.sender.clone());
+        subscriber.channels.insert(channel.clone());
+        Self::confirm("subscribe", channel, subscriber.channels.len())
+    }
+
+    fn unsubscribe(&self, session: &mut Session, channel: String) -> Reply {
+        let Some(subscriber) = &mut session.subscriber else {
+            return Self::confirm("unsubscribe", channel, 0);
+        };
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(subscribers) = channels.get_mut(&channel) {
+            subscribers.remove(&subscriber.id);
+            if subscribers.is_empty() {
+                channels.remove(&channel);
+            }
+        }
+        subscriber.channels.remove(&channel);
+        Self::confirm("unsubscribe", channel, subscriber.channels.len())
+    }
+
+    /// Unsubscribes the session from all its channels, for when its connection closes.
+    fn unsubscribe_all(&self, session: &mut Session) {
+        let channels = match &session.subscriber {
+            Some(subscriber) => subscriber.channels.clone(),
+            None => return,
+        };
+        for channel in channels {
+            self.unsubscribe(session, channel);
+        }
+    }
+
+    /// Delivers the message to the subscribers of the channel, returning how many there were.
+    fn publish(&self, channel: &str, message: &str) -> Reply {
+        let channels = self.channels.lock().unwrap();
+        let Some(subscribers) = channels.get(channel) else {
+            return Reply::Int(0);
+        };
+        let message = Message {
+            channel: channel.into(),
+            message: message.into(),
+        };
+        // Sending only fails for a session that's gone, which unsubscribes it right after
+        let delivered = subscribers
+            .values()
+            .filter(|sender| sender.send(message.clone()).is_ok())
+            .count();
+        Reply::Int(delivered as i64)
+    }
+
+    fn confirm(kind: &str, channel: String, subscriptions: usize) -> Reply {
+        Reply::Array(vec![
+            Reply::Value(kind.into()),
+            Reply::Value(channel),
+            Reply::Int(subscriptions as i64),
+        ])
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    commands: AtomicU64,
+    by_name: Mutex<BTreeMap<&'static str, u64>>,
+    last_recovery: Mutex<Option<Duration>>,
+    evicted: AtomicU64,
+}
+
+impl Stats {
+    fn record(&self, command: &Command) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+        *self
+            .by_name
+            .lock()
+            .unwrap()
+            .entry(command.name())
+            .or_default() += 1;
+    }
+}
+
+#[derive(Debug)]
+struct Log<W: WriteAheadLog> {
+    /// ID the next transaction gets.
+    transaction_id: usize,
+    write_ahead_log: W,
+}
+
+impl<W: WriteAheadLog> Log<W> {
+    /// ID of the last transaction applied, nil before the first.
+    fn last_id(&self) -> Reply {
+        self.transaction_id
+            .checked_sub(1)
+            .map_or(Reply::Nil, |id| Reply::Int(id as i64))
+    }
+}
+
+/// Mutating commands serialize through the `log` mutex, read-only commands only take a shared
+/// lock on `state`, so concurrent reads don't block each other.
+///
+/// Lock order: `log` is always acquired before `state`. Never lock `log` while holding `state`.
+#[derive(Debug)]
+struct Server<W: WriteAheadLog> {
+    log: Mutex<Log<W>>,
+    state: RwLock<State>,
+    clock: Arc<dyn Clock>,
+    stats: Stats,
+    pubsub: PubSub,
+    keyspace_notifications: bool,
+    max_sizes: MaxSizes,
+    /// Picks the keys RANDOMKEY returns.
+    rng: Mutex<StdRng>,
+    shutting_down: AtomicBool,
+    replica: bool,
+    /// Answers writes without logging or applying them, see `with_dry_run`.
+    dry_run: bool,
+    /// Commands holding a lock for longer than this are logged, see `with_slow_command_threshold`.
+    slow_command_threshold: Option<Duration>,
+    /// Set while `run` recovers the state, during which clients only get answers to PING.
+    recovering: AtomicBool,
+    /// Held while saving a snapshot, so a checkpoint and a shutdown can't write it at once.
+    saving_snapshot: Mutex<()>,
+    /// See `spawn_reclaimer`.
+    reclaimer: Sender<Vec<(String, Entry)>>,
+    recovery_progress: Option<ProgressCallback>,
+}
+
+/// How far `Server::recover` has gotten.
+#[derive(Clone, Copy, Debug)]
+struct RecoveryProgress {
+    /// Transactions applied
+    records: usize,
+    /// Bytes of the write-ahead log read, see `WriteAheadLog::replayed_bytes`
+    bytes: u64,
+    total_bytes: u64,
+}
+
+impl RecoveryProgress {
+    /// Estimate of how much of the log has been read, None if its size is unknown.
+    fn percent(&self) -> Option<f64> {
+        (self.total_bytes > 0)
+            .then(|| (self.bytes as f64 * 100.0 / self.total_bytes as f64).min(100.0))
+    }
+
+    fn log(&self) {
+        info!(
+            records = self.records,
+            bytes = self.bytes,
+            total_bytes = self.total_bytes,
+            percent:% = self
+                .percent()
+                .map_or("unknown".into(), |p| format!("{:.1}", p));
+            "Recovering write-ahead log"
+        );
+    }
+}
+
+/// See `Server::with_recovery_progress`.
+struct ProgressCallback {
+    every_records: usize,
+    callback: Box<dyn Fn(&RecoveryProgress) + Send + Sync>,
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProgressCallback")
+            .field("every_records", &self.every_records)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W: WriteAheadLog> Server<W> {
+    fn new(write_ahead_log: W) -> Self {
+        Self {
+            log: Mutex::new(Log {
+                transaction_id: 0,
+                write_ahead_log,
+            }),
+            state: RwLock::new(State::new()),
+            clock: Arc::new(SystemClock),
+            stats: Stats::default(),
+            pubsub: PubSub::default(),
+            keyspace_notifications: false,
+            max_sizes: MaxSizes::default(),
+            rng: Mutex::new(StdRng::from_entropy()),
+            shutting_down: AtomicBool::new(false),
+            replica: false,
+            dry_run: false,
+            slow_command_threshold: None,
+            recovering: AtomicBool::new(false),
+            saving_snapshot: Mutex::new(()),
+            reclaimer: spawn_reclaimer(),
+            recovery_progress: None,
+        }
+    }
+
+    #[cfg(test)]
+    fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Evicts the least recently used keys whenever a write takes the state over the limits. See
+    /// `State::evict`.
+    fn with_limits(self, limits: Limits) -> Self {
+        self.state.write().unwrap().set_limits(limits);
+        self
+    }
+
+    /// Rejects writes of keys or values larger than the given sizes, before they're logged.
+    fn with_max_sizes(mut self, max_sizes: MaxSizes) -> Self {
+        self.max_sizes = max_sizes;
+        self
+    }
+
+    /// Seeds the random number generator, so RANDOMKEY returns the same keys every run.
+    fn with_seed(self, seed: u64) -> Self {
+        *self.rng.lock().unwrap() = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    /// Publishes an event to the `__keyspace@<db>__:<key>` channel whenever a write changes a key,
+    /// with the event, like "set" or "del", as the message. Off by default, as every change then
+    /// costs a lookup of the channel's subscribers.
+    ///
+    /// Only changes made by clients are published, not the ones replayed by recovery.
+    fn with_keyspace_notifications(mut self) -> Self {
+        self.keyspace_notifications = true;
+        self
+    }
+
+    /// Tracks when every key was last accessed, for OBJECT IDLETIME. Uses the server's clock, so
+    /// call `with_clock` first.
+    fn with_idle_times(self) -> Self {
+        let clock = Arc::clone(&self.clock);
+        self.state.write().unwrap().track_idle_times(clock);
+        self
+    }
+
+    /// Makes the server a read-only replica. It rejects writes, and its state only changes by
+    /// calling `recover` to pick up what the primary appended to the log since.
+    ///
+    /// Compacting renumbers the transactions, so replicas have to be restarted after the primary
+    /// compacts its log.
+    fn into_replica(mut self) -> Self {
+        self.replica = true;
+        self
+    }
+
+    /// Makes the server answer mutating commands with the replies they would get, without
+    /// logging or applying them, so queries can be tried out against real data. Unlike on a
+    /// replica, the commands are still parsed, validated and executed. See `simulate`.
+    fn with_dry_run(mut self) -> Self {
+        self.dry_run = true;
+        self
+    }
+
+    /// Logs a warning for every command that holds the state or the log lock for at least
+    /// `threshold`, stalling the commands waiting on it. Reads only share the state lock, so a
+    /// slow one holds up writes, but not other reads.
+    fn with_slow_command_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_command_threshold = Some(threshold);
+        self
+    }
+
+    /// Calls `callback` every `every_records` transactions recovery applies. Without one, recovery
+    /// logs its progress every `RECOVERY_PROGRESS_INTERVAL`.
+    fn with_recovery_progress(
+        mut self,
+        every_records: usize,
+        callback: impl Fn(&RecoveryProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.recovery_progress = Some(ProgressCallback {
+            every_records: every_records.max(1),
+            callback: Box::new(callback),
+        });
+        self
+    }
+
+    /// Warns about a command that has held a lock since `locked` for too long.
+    fn check_slow(&self, command_type: &str, locked: Instant) {
+        let held = locked.elapsed();
+        if self
+            .slow_command_threshold
+            .is_some_and(|threshold| held >= threshold)
+        {
+            warn!(
+                command_type = command_type,
+                held_ms = held.as_millis() as u64;
+                "Slow command held the lock"
+            );
+        }
+    }
+
+    /// Writes the current state and the ID of the next transaction to a snapshot file, so
+    /// recovery can start from it instead of replaying the entire write-ahead log. Returns that
+    /// ID.
+    ///
+    /// The log is synced first, so it never ends before a snapshot taken from it. Writes are only
+    /// held up while the state is serialized, not while the snapshot is written to the file.
+    fn save_snapshot(&self, path: impl AsRef<Path>) -> Result<usize> {
+        let _saving = self.saving_snapshot.lock().unwrap();
+        let (transaction_id, snapshot) = {
+            // Holding the log lock keeps writes out while the state is serialized
+            let mut log = self.log.lock().unwrap();
+            log.write_ahead_log.flush()?;
+            let state = self.state.read().unwrap();
+            let snapshot = serde_json::to_vec(&SnapshotRef {
+                transaction_id: log.transaction_id,
+                state: &state,
+            })?;
+            (log.transaction_id, snapshot)
+        };
+        write_atomically(path.as_ref(), &snapshot)?;
+        info!(transaction_id = transaction_id; "Saved snapshot");
+        Ok(transaction_id)
+    }
+
+    /// Saves a snapshot, then lets the write-ahead log drop the transactions it holds, which
+    /// bounds how much a restart replays.
+    fn checkpoint(&self, path: &Path) -> Result<usize> {
+        let transaction_id = self.save_snapshot(path)?;
+        self.log
+            .lock()
+            .unwrap()
+            .write_ahead_log
+            .checkpoint(transaction_id)?;
+        Ok(transaction_id)
+    }
+
+    /// Starts a thread checkpointing to the snapshot at `path` whenever `policy` says one is due,
+    /// until the server shuts down. Checkpoints only happen once something was logged since the
+    /// last one, or since the thread started.
+    fn spawn_checkpointer(
+        server: &Arc<Self>,
+        path: PathBuf,
+        policy: CheckpointPolicy,
+    ) -> thread::JoinHandle<()>
+    where
+        W: Send + 'static,
+    {
+        let server = Arc::clone(server);
+        thread::spawn(move || {
+            let mut last_id = server.log.lock().unwrap().transaction_id;
+            let mut last_at = server.clock.now();
+            while !server.is_shutting_down() {
+                thread::sleep(CHECKPOINT_POLL_INTERVAL);
+                let transaction_id = server.log.lock().unwrap().transaction_id;
+                let elapsed = server.clock.now().saturating_sub(last_at);
+                if !policy.is_due(transaction_id.saturating_sub(last_id), elapsed) {
+                    continue;
+                }
+                match server.checkpoint(&path) {
+                    Ok(transaction_id) => last_id = transaction_id,
+                    // Shutting down writes a final snapshot anyway
+                    Err(e) if server.is_shutting_down() => {
+                        debug!(error:% = e; "Stopped checkpointing")
+                    }
+                    Err(e) => warn!(error:% = format!("{:#}", e); "Failed to checkpoint"),
+                }
+                last_at = server.clock.now();
+            }
+        })
+    }
+
+    /// Loads a snapshot written by `save_snapshot`. Call `recover` afterwards to replay the
+    /// transactions logged after it was taken.
+    ///
+    /// A snapshot only matches the write-ahead log it was taken from. Compacting renumbers the
+    /// transactions, so take a new snapshot after compacting. `recover` fails when the log doesn't
+    /// continue where the snapshot left off.
+    fn load_snapshot(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.restore_snapshot(read_snapshot(path)?)
+    }
+
+    fn restore_snapshot(&self, snapshot: Snapshot) -> Result<()> {
+        if snapshot.state.databases.len() != DATABASES {
+            return Err(anyhow!(
+                "Snapshot has {} databases, expected {}",
+                snapshot.state.databases.len(),
+                DATABASES
+            ));
+        }
+
+        let mut log = self.log.lock().unwrap();
+        let mut state = self.state.write().unwrap();
+        log.transaction_id = snapshot.transaction_id;
+        let limits = state.limits;
+        let access_clock = state.access_clock.take();
+        *state = snapshot.state;
+        state.set_limits(limits);
+        if let Some(clock) = access_clock {
+            state.track_idle_times(clock);
+        }
+        info!(transaction_id = log.transaction_id; "Loaded snapshot");
+        Ok(())
+    }
+
+    /// Replays the write-ahead log, applying only the transactions that haven't been applied yet.
+    /// Calling this again, or on a server that's already up to date, leaves the state unchanged.
+    ///
+    /// Fails unless the log holds the last transaction already applied, if any, and continues
+    /// from there without gaps. Otherwise the log doesn't belong with the state it's replayed
+    /// onto, like a snapshot taken after the end of the log, or the log before compacting.
+    fn recover(&self) -> Result<()> {
+        self.recover_until(RecoverTarget::End)
+    }
+
+    /// `recover`, but stops at `target`, for example to undo a bad write by recovering to just
+    /// before it. Fails if the state is already past the target, as when a snapshot holds
+    /// transactions after it, see `RecoverTarget::admits`.
+    ///
+    /// Stopping early discards the transactions after the target from the log, so new ones don't
+    /// follow them, see `WriteAheadLog::discard_from`. The IDs of the others stay the same, so
+    /// snapshots up to the target still match the log. Dry runs leave the log be.
+    fn recover_until(&self, target: RecoverTarget) -> Result<()> {
+        let started = Instant::now();
+        let mut log = self.log.lock().unwrap();
+        let mut state = self.state.write().unwrap();
+        let Log {
+            transaction_id,
+            write_ahead_log,
+        } = &mut *log;
+
+        // Starts from the last applied transaction, to check the log gets that far
+        let mut continues = *transaction_id == 0;
+        let mut skipped = 0;
+        let mut recovered = 0;
+        let mut stopped = false;
+        let replayed = write_ahead_log.replayed_bytes();
+        let total_bytes = write_ahead_log.size()?;
+        let mut reported = Instant::now();
+        for result in write_ahead_log.replay_from(transaction_id.saturating_sub(1))? {
+            let transaction = result?;
+
+            match (transaction.id, *transaction_id) {
+                (loaded, internal) if loaded < internal && !target.includes(&transaction) => {
+                    return Err(anyhow!(
+                        "Transaction {} is already applied, so recovery can't stop before it",
+                        loaded
+                    ));
+                }
+                // Already applied, for example because it's included in a snapshot
+                (loaded, internal) if loaded < internal => {
+                    continues |= loaded + 1 == internal;
+                    skipped += 1;
+                    continue;
+                }
+                (loaded, internal) if loaded > internal => {
+                    return Err(anyhow!(
+                        "Unexpected transaction ID: got {}, expected {}",
+                        transaction.id,
+                        transaction_id
+                    ));
+                }
+                _ if transaction.databases().iter().any(|db| *db >= DATABASES) => {
+                    return Err(anyhow!(
+                        "Transaction {} targets database {}, only {} are available",
+                        transaction.id,
+                        transaction.databases().iter().max().unwrap(),
+                        DATABASES
+                    ));
+                }
+                _ if !target.includes(&transaction) => {
+                    stopped = true;
+                    break;
+                }
+                _ => {
+                    continues = true;
+                    state.apply(transaction.db, &transaction.command);
+                    self.reclaim(state.take_unlinked());
+                    *transaction_id += 1;
+                    recovered += 1;
+                    let progress = || RecoveryProgress {
+                        records: recovered,
+                        bytes: replayed.as_ref().map_or(0, |r| r.load(Ordering::Relaxed)),
+                        total_bytes,
+                    };
+                    match &self.recovery_progress {
+                        Some(reporter) if recovered % reporter.every_records == 0 => {
+                            (reporter.callback)(&progress())
+                        }
+                        Some(_) => {}
+                        None if reported.elapsed() >= RECOVERY_PROGRESS_INTERVAL => {
+                            progress().log();
+                            reported = Instant::now();
+                        }
+                        None => {}
+                    }
+                    debug!(
+                        transaction_id = transaction.id,
+                        db = transaction.db,
+                        command_type = transaction.command.name();
+                        "Recovered transaction"
+                    );
+                }
+            }
+        }
+        if !continues {
+            return Err(anyhow!(
+                "Write-ahead log ends before transaction {}, which was already applied",
+                *transaction_id - 1
+            ));
+        }
+        // Replaying can go over the limit, as the evictions that kept it aren't in the log
+        self.record_evictions(state.evict(self.clock.now()));
+        let duration = started.elapsed();
+        *self.stats.last_recovery.lock().unwrap() = Some(duration);
+        if recovered > 0 || skipped > 0 {
+            info!(
+                recovered = recovered,
+                skipped = skipped,
+                transaction_id = *transaction_id,
+                duration_ms = duration.as_millis() as u64;
+                "Recovered write-ahead log"
+            );
+        }
+        if stopped && !self.dry_run {
+            write_ahead_log.discard_from(*transaction_id)?;
+        }
+        Ok(())
+    }
+
+    fn execute(&self, session: &mut Session, query: &str) -> Result<Reply> {
+        let command = Self::parse(query).inspect_err(|_| session.abort())?;
+        self.execute_command(session, command)
+    }
+
+    /// `execute_command` for a command sent by a client. While recovering, only PING is answered,
+    /// so health checks pass before the state is there to serve.
+    fn execute_for_client(&self, session: &mut Session, command: Command) -> Result<Reply> {
+        if self.recovering.load(Ordering::SeqCst) && !matches!(command, Command::Ping { .. }) {
+            return Err(anyhow!("LOADING server is recovering its state"));
+        }
+        self.execute_command(session, command)
+    }
+
+    /// Sends the command down the write path if it mutates state, or answers it from the current
+    /// state otherwise.
+    fn execute_command(&self, session: &mut Session, command: Command) -> Result<Reply> {
+        let started = Instant::now();
+        let (command_type, db) = (command.name(), session.db);
+        let result = self.dispatch(session, command);
+        debug!(
+            command_type = command_type,
+            db = db,
+            duration_us = started.elapsed().as_micros() as u64,
+            failed = matches!(result, Err(_) | Ok(Reply::Error(_)));
+            "Executed command"
+        );
+        result
+    }
+
+    fn dispatch(&self, session: &mut Session, command: Command) -> Result<Reply> {
+        self.check_running()?;
+        self.stats.record(&command);
+        let now = self.clock.now();
+        if session.is_subscribed()
+            && !matches!(
+                command,
+                Command::Subscribe { .. } | Command::Unsubscribe { .. } | Command::Ping { .. }
+            )
+        {
+            return Err(anyhow!(
+                "ERR only SUBSCRIBE, UNSUBSCRIBE and PING are allowed while subscribed"
+            ));
+        }
+        if let Some(queued) = &mut session.queued {
+            match command {
+                Command::Multi | Command::Exec | Command::Discard | Command::Watch { .. } => {}
+                Command::Restore { .. }
+                | Command::ExpireSweep
+                | Command::Subscribe { .. }
+                | Command::Unsubscribe { .. } => {
+                    session.aborted = true;
+                    return Err(anyhow!(
+                        "ERR {} can't be part of a transaction",
+                        command.name().to_uppercase()
+                    ));
+                }
+                // Time relative arguments are resolved once the transaction executes
+                _ => {
+                    queued.push(command);
+                    return Ok(Reply::Queued);
+                }
+            }
+        }
+        let command = command.resolve(now);
+        match command {
+            Command::Multi if session.queued.is_some() => {
+                Err(anyhow!("ERR MULTI calls can not be nested"))
+            }
+            Command::Multi => {
+                session.queued = Some(vec![]);
+                Ok(Reply::Ok)
+            }
+            Command::Exec => {
+                let watched = std::mem::take(&mut session.watched);
+                match (session.aborted, session.reset()) {
+                    (_, None) => Err(anyhow!("ERR EXEC without MULTI")),
+                    (true, Some(_)) => Err(anyhow!(
+                        "EXECABORT Transaction discarded because of previous errors"
+                    )),
+                    (false, Some(commands)) => self.exec(session, &watched, commands, now),
+                }
+            }
+            Command::Discard => match session.reset() {
+                Some(_) => Ok(Reply::Ok),
+                None => Err(anyhow!("ERR DISCARD without MULTI")),
+            },
+            Command::Watch { .. } if session.queued.is_some() => {
+                Err(anyhow!("ERR WATCH inside MULTI is not allowed"))
+            }
+            Command::Watch { keys } => {
+                let state = self.state.read().unwrap();
+                let database = &state.databases[session.db];
+                for key in keys {
+                    let version = database.version(&key);
+                    session.watched.push((session.db, key, version));
+                }
+                Ok(Reply::Ok)
+            }
+            Command::Unwatch => {
+                session.watched.clear();
+                Ok(Reply::Ok)
+            }
+            Command::Subscribe { channel } => Ok(self.pubsub.subscribe(session, channel)),
+            Command::Unsubscribe { channel } => Ok(self.pubsub.unsubscribe(session, channel)),
+            Command::Publish { channel, message } => Ok(self.pubsub.publish(&channel, &message)),
+            Command::Ping { message } => Ok(message.map_or(Reply::Pong, Reply::Value)),
+            Command::Echo { message } => Ok(Reply::Value(message)),
+            Command::LastId => Ok(self.log.lock().unwrap().last_id()),
+            Command::ExpireSweep => Ok(Reply::Int(self.expire_keys()? as i64)),
+            Command::ListCommands => Ok(Reply::Array(
+                COMMANDS.iter().map(CommandSpec::reply).collect(),
+            )),
+            Command::Info => {
+                let log = self.log.lock().unwrap();
+                self.info(&log, &self.state.read().unwrap())
+            }
+            Command::Dump => dump(&self.state.read().unwrap(), now),
+            Command::RandomKey => Ok(self.random_key(&self.state.read().unwrap(), session.db, now)),
+            Command::Restore { dump } => {
+                self.write(restore(&dump, now)?, now)?;
+                Ok(Reply::Ok)
+            }
+            Command::Select { index } => {
+                session.db = index;
+                Ok(Reply::Ok)
+            }
+            _ if command.is_mutating() => {
+                Ok(self.write(vec![(session.db, command)], now)?.remove(0))
+            }
+            _ => Ok(self.read(session.db, &command, now)),
+        }
+    }
+
+    /// Answers a read-only command. This only needs a shared lock on the state, and never touches
+    /// the write-ahead log or the transaction ID.
+    fn read(&self, db: usize, command: &Command, now: u64) -> Reply {
+        let state = self.state.read().unwrap();
+        let locked = Instant::now();
+        let reply = state.query(db, command, now);
+        self.check_slow(command.name(), locked);
+        reply
+    }
+
+    /// Executes several queries, logging all their mutations as a single transaction, so they're
+    /// recovered atomically. If any query fails to parse, none of them are executed.
+    fn execute_batch(&self, session: &mut Session, queries: &[&str]) -> Result<Vec<Reply>> {
+        self.check_running()?;
+        let now = self.clock.now();
+        let commands = queries
+            .iter()
+            .map(|query| Ok(Self::parse(query)?.resolve(now)))
+            .collect::<Result<Vec<Command>>>()?;
+        if let Some(command) = commands.iter().find(|command| {
+            matches!(
+                command,
+                Command::Restore { .. }
+                    | Command::ExpireSweep
+                    | Command::Multi
+                    | Command::Exec
+                    | Command::Discard
+                    | Command::Watch { .. }
+                    | Command::Unwatch
+                    | Command::Subscribe { .. }
+                    | Command::Unsubscribe { .. }
+            )
+        }) {
+            return Err(anyhow!(
+                "ERR {} can't be part of a batch",
+                command.name().to_uppercase()
+            ));
+        }
+        commands
+            .iter()
+            .for_each(|command| self.stats.record(command));
+        self.write(Self::assign_databases(session, commands), now)
+    }
+
+    /// Pairs every command with the database it runs against, following the SELECTs among them.
+    fn assign_databases(session: &mut Session, commands: Vec<Command>) -> Vec<(usize, Command)> {
+        commands
+            .into_iter()
+            .map(|command| {
+                if let Command::Select { index } = command {
+                    session.db = index;
+                }
+                (session.db, command)
+            })
+            .collect()
+    }
+
+    /// Executes the commands queued by a MULTI as one batch, unless one of the `watched` keys
+    /// changed since, in which case nothing is executed and the reply is nil.
+    ///
+    /// A key that's created and deleted again while watched doesn't count as changed.
+    fn exec(
+        &self,
+        session: &mut Session,
+        watched: &[(usize, String, Option<u64>)],
+        commands: Vec<Command>,
+        now: u64,
+    ) -> Result<Reply> {
+        let commands = commands
+            .into_iter()
+            .map(|command| command.resolve(now))
+            .collect();
+        let commands = Self::assign_databases(session, commands);
+        self.check_writable(&commands)?;
+        let mut log = self.log.lock().unwrap();
+        let locked = Instant::now();
+        self.check_running()?;
+
+        // Checked under the log lock, so no write can come in between
+        {
+            let state = self.state.read().unwrap();
+            if watched
+                .iter()
+                .any(|(db, key, version)| state.databases[*db].version(key) != *version)
+            {
+                return Ok(Reply::Nil);
+            }
+        }
+        let replies = self.write_locked(&mut log, commands, now);
+        self.check_slow("exec", locked);
+        Ok(Reply::Array(replies?))
+    }
+
+    /// Logs the mutations among `commands` as a single transaction, a `Command::Batch` if there's
+    /// more than one, and applies them, returning the reply to each command. Read-only commands
+    /// see the mutations that precede them. Every command is paired with the database it runs
+    /// against.
+    fn write(&self, commands: Vec<(usize, Command)>, now: u64) -> Result<Vec<Reply>> {
+        self.check_writable(&commands)?;
+        let command_type = match commands.as_slice() {
+            [(_, command)] => command.name(),
+            _ => "batch",
+        };
+        let mut log = self.log.lock().unwrap();
+        let locked = Instant::now();
+        // Writes that were waiting for the lock while the server shut down
+        self.check_running()?;
+        let replies = self.write_locked(&mut log, commands, now);
+        self.check_slow(command_type, locked);
+        replies
+    }
+
+    fn check_writable(&self, commands: &[(usize, Command)]) -> Result<()> {
+        if self.replica && commands.iter().any(|(_, command)| command.is_mutating()) {
+            return Err(anyhow!(
+                "READONLY You can't write against a read only replica"
+            ));
+        }
+        let exceeds = |max: Option<usize>, s: &str| max.is_some_and(|max| max > 0 && s.len() > max);
+        for (_, command) in commands {
+            if command
+                .created_keys()
+                .iter()
+                .any(|key| exceeds(self.max_sizes.key_bytes, key))
+            {
+                return Err(anyhow!("ERR key is larger than the maximum key size"));
+            }
+            if command
+                .values()
+                .iter()
+                .any(|value| exceeds(self.max_sizes.value_bytes, value))
+            {
+                return Err(anyhow!("ERR value is larger than the maximum value size"));
+            }
+        }
+        Ok(())
+    }
+
+    /// `write` for a caller that already holds the log lock.
+    fn write_locked(
+        &self,
+        log: &mut Log<W>,
+        commands: Vec<(usize, Command)>,
+        now: u64,
+    ) -> Result<Vec<Reply>> {
+        let mut expired: Vec<(usize, Command)> = vec![];
+        {
+            let state = self.state.read().unwrap();
+            // Within a batch the outcome depends on the preceding commands, so only a lone command
+            // can be answered up front
+            if let [(db, command)] = commands.as_slice() {
+                if let Some(reply) = state.databases[*db].without_effect(command, now) {
+                    return Ok(vec![reply]);
+                }
+            }
+
+            for (db, command) in commands.iter().filter(|(_, c)| c.is_mutating()) {
+                for key in state.databases[*db].expired(command, now) {
+                    let delete = (*db, Command::Delete { key: key.into() });
+                    if !expired.contains(&delete) {
+                        expired.push(delete);
+                    }
+                }
+            }
+        }
+
+        if self.dry_run {
+            return self.simulate(log, &commands, now);
+        }
+
+        let mut mutations = expired.clone();
+        for (db, command) in commands.iter().filter(|(_, c)| c.is_mutating()) {
+            mutations.push((*db, command.clone()));
+            mutations.extend(command.immediate_delete(now).map(|delete| (*db, delete)));
+        }
+        Self::append(log, mutations, now)?;
+
+        let mut state = self.state.write().unwrap();
+        self.delete_expired(&mut state, &expired);
+        let replies = commands
+            .iter()
+            .map(|(db, command)| match command {
+                Command::Select { .. } | Command::Unwatch => Ok(Reply::Ok),
+                Command::Info => self.info(log, &state),
+                Command::Dump => dump(&state, now),
+                Command::RandomKey => Ok(self.random_key(&state, *db, now)),
+                Command::LastId => Ok(log.last_id()),
+                Command::Publish { channel, message } => Ok(self.pubsub.publish(channel, message)),
+                Command::Unlink { .. } => {
+                    let reply = state.apply(*db, command);
+                    let unlinked = state.take_unlinked();
+                    for (key, _) in &unlinked {
+                        self.notify(*db, key, "del");
+                    }
+                    self.reclaim(unlinked);
+                    Ok(reply)
+                }
+                _ if command.is_mutating() => {
+                    let reply = state.apply(*db, command);
+                    for (key, event) in command.events(&reply) {
+                        self.notify(*db, key, event);
+                    }
+                    if let Some(delete) = command.immediate_delete(now) {
+                        self.delete_expired(&mut state, &[(*db, delete)]);
+                    }
+                    Ok(reply)
+                }
+                _ => Ok(state.query(*db, command, now)),
+            })
+            .collect();
+        // Cache behaviour rather than a mutation, so it isn't logged
+        self.record_evictions(state.evict(now));
+        replies
+    }
+
+    /// Answers the commands as `write_locked` would, but applies the mutations to a copy of only
+    /// the keys they touch, leaving the log and the state alone. Commands reading the whole
+    /// database, like KEYS and DBSIZE, see it without the mutations. PUBLISH reaches no one.
+    fn simulate(
+        &self,
+        log: &Log<W>,
+        commands: &[(usize, Command)],
+        now: u64,
+    ) -> Result<Vec<Reply>> {
+        let state = self.state.read().unwrap();
+        let mut copy = State::new();
+        for (db, command) in commands {
+            for key in command.keys() {
+                if let Some(entry) = state.databases[*db].get(key, now) {
+                    let entry = Entry {
+                        expires_at: entry.expires_at,
+                        ..Entry::new(entry.value.clone())
+                    };
+                    copy.databases[*db].kv.insert(key.into(), entry);
+                }
+            }
+        }
+        commands
+            .iter()
+            .map(|(db, command)| match command {
+                Command::Select { .. } | Command::Unwatch => Ok(Reply::Ok),
+                Command::Info => self.info(log, &state),
+                Command::Dump => dump(&state, now),
+                Command::RandomKey => Ok(self.random_key(&state, *db, now)),
+                Command::LastId => Ok(log.last_id()),
+                Command::Publish { .. } => Ok(Reply::Int(0)),
+                _ if command.is_mutating() => Ok(copy.apply(*db, command)),
+                _ if command.keys().is_empty() => Ok(state.query(*db, command, now)),
+                _ => Ok(copy.query(*db, command, now)),
+            })
+            .collect()
+    }
+
+    /// A key picked uniformly at random among the unexpired keys of the database, or nil if
+    /// there are none. Keys aren't indexed by position, so this reservoir samples them in a
+    /// single pass over the database, which takes O(n) in the number of keys.
+    fn random_key(&self, state: &State, db: usize, now: u64) -> Reply {
+        let mut rng = self.rng.lock().unwrap();
+        state.databases[db]
+            .kv
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .choose(&mut *rng)
+            .into()
+    }
+
+    /// Logs the mutations as a single transaction, wrapped in a `Command::Batch` if there's more
+    /// than one.
+    fn append(log: &mut Log<W>, mut mutations: Vec<(usize, Command)>, now: u64) -> Result<()> {
+        let (db, command) = match mutations.len() {
+            0 => return Ok(()),
+            1 => mutations.remove(0),
+            _ => (
+                0,
+                Command::Batch {
+                    commands: mutations,
+                },
+            ),
+        };
+        log.write_ahead_log.append(&Transaction {
+            id: log.transaction_id,
+            db,
+            command,
+            timestamp: now,
+        })?;
+        log.transaction_id += 1;
+        Ok(())
+    }
+
+    /// Applies the deletes of expired keys.
+    fn delete_expired(&self, state: &mut State, deletes: &[(usize, Command)]) {
+        for (db, command) in deletes {
+            state.apply(*db, command);
+            if let Command::Delete { key } = command {
+                self.notify(*db, key, "expired");
+            }
+        }
+    }
+
+    /// Deletes up to `EXPIRE_BATCH` keys whose time to live ran out, so they don't take up memory
+    /// until a command happens to touch them. The deletes are logged like any other. Returns how
+    /// many keys were deleted.
+    ///
+    /// Replicas leave this to the primary, and pick up its deletes from the log. Dry runs leave
+    /// the expired keys be, as they're invisible to commands either way.
+    fn expire_keys(&self) -> Result<usize> {
+        if self.replica || self.dry_run {
+            return Ok(0);
+        }
+        let mut log = self.log.lock().unwrap();
+        self.check_running()?;
+        let now = self.clock.now();
+        let deletes: Vec<(usize, Command)> = {
+            let state = self.state.read().unwrap();
+            state
+                .databases
+                .iter()
+                .enumerate()
+                .flat_map(|(db, database)| {
+                    database
+                        .expiring(now)
+                        .map(move |key| (db, Command::Delete { key: key.clone() }))
+                })
+                .take(EXPIRE_BATCH)
+                .collect()
+        };
+        Self::append(&mut log, deletes.clone(), now)?;
+        self.delete_expired(&mut self.state.write().unwrap(), &deletes);
+        Ok(deletes.len())
+    }
+
+    /// Starts a thread calling `WriteAheadLog::tick` every `SYNC_TICK_INTERVAL`, until the server
+    /// shuts down.
+    fn spawn_ticker(server: &Arc<Self>) -> thread::JoinHandle<()>
+    where
+        W: Send + 'static,
+    {
+        let server = Arc::clone(server);
+        thread::spawn(move || {
+            while !server.is_shutting_down() {
+                thread::sleep(SYNC_TICK_INTERVAL);
+                if let Err(e) = server.log.lock().unwrap().write_ahead_log.tick() {
+                    warn!(error:% = format!("{:#}", e); "Failed to sync the write-ahead log");
+                }
+            }
+        })
+    }
+
+    /// Starts a thread calling `expire_keys` every `interval`, until the server shuts down. A sweep
+    /// that deletes a full batch is followed by another right away.
+    fn spawn_expirer(server: &Arc<Self>, interval: Duration) -> thread::JoinHandle<()>
+    where
+        W: Send + 'static,
+    {
+        let server = Arc::clone(server);
+        thread::spawn(move || {
+            while !server.is_shutting_down() {
+                match server.expire_keys() {
+                    Ok(EXPIRE_BATCH) => continue,
+                    Ok(_) => {}
+                    Err(e) if server.is_shutting_down() => {
+                        debug!(error:% = e; "Stopped expiring keys")
+                    }
+                    Err(e) => warn!(error:% = format!("{:#}", e); "Failed to delete expired keys"),
+                }
+                thread::sleep(interval);
+            }
+        })
+    }
+
+    fn reclaim(&self, unlinked: Vec<(String, Entry)>) {
+        if !unlinked.is_empty() {
+            // Dropping them right here is the fallback should the reclaimer be gone
+            let _ = self.reclaimer.send(unlinked);
+        }
+    }
+
+    fn notify(&self, db: usize, key: &str, event: &str) {
+        if self.keyspace_notifications {
+            let channel = format!("__keyspace@{}__:{}", db, key);
+            self.pubsub.publish(&channel, event);
+        }
+    }
+
+    fn record_evictions(&self, evicted: usize) {
+        self.stats
+            .evicted
+            .fetch_add(evicted as u64, Ordering::Relaxed);
+    }
+
+    /// Server statistics as `name:value` lines.
+    fn info(&self, log: &Log<W>, state: &State) -> Result<Reply> {
+        let mut lines = vec![format!(
+            "commands_processed:{}",
+            self.stats.commands.load(Ordering::Relaxed)
+        )];
+        for (name, count) in self.stats.by_name.lock().unwrap().iter() {
+            lines.push(format!("commands_{}:{}", name, count));
+        }
+        lines.push(format!("keys:{}", state.len()));
+        if state.limits.max_bytes.is_some() {
+            lines.push(format!("used_bytes:{}", state.bytes));
+        }
+        lines.push(format!(
+            "evicted_keys:{}",
+            self.stats.evicted.load(Ordering::Relaxed)
+        ));
+        lines.push(format!("wal_size_bytes:{}", log.write_ahead_log.size()?));
+        if let Some(syncs) = log.write_ahead_log.syncs() {
+            lines.push(format!("wal_syncs:{}", syncs));
+        }
+        if let Some(duration) = *self.stats.last_recovery.lock().unwrap() {
+            lines.push(format!("last_recovery_ms:{}", duration.as_millis()));
+        }
+        Ok(Reply::Value(lines.join("\n")))
+    }
+
+    /// The counters INFO reports, in the Prometheus text exposition format.
+    fn metrics(&self) -> Result<String> {
+        let log = self.log.lock().unwrap();
+        let state = self.state.read().unwrap();
+        let mut metrics = vec![
+            "# HELP keyval_commands_total Commands processed.".to_string(),
+            "# TYPE keyval_commands_total counter".to_string(),
+        ];
+        for (name, count) in self.stats.by_name.lock().unwrap().iter() {
+            metrics.push(format!(
+                "keyval_commands_total{{command=\"{}\"}} {}",
+                name, count
+            ));
+        }
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            metrics.push(format!("# HELP keyval_{} {}", name, help));
+            metrics.push(format!("# TYPE keyval_{} {}", name, kind));
+            metrics.push(format!("keyval_{} {}", name, value));
+        };
+        metric(
+            "keys",
+            "gauge",
+            "Keys across all databases.",
+            state.len().to_string(),
+        );
+        if state.limits.max_bytes.is_some() {
+            metric(
+                "used_bytes",
+                "gauge",
+                "Approximate size of the keys and values.",
+                state.bytes.to_string(),
+            );
+        }
+        metric(
+            "evicted_keys_total",
+            "counter",
+            "Keys evicted to stay within the limits.",
+            self.stats.evicted.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "wal_size_bytes",
+            "gauge",
+            "Size of the write-ahead log.",
+            log.write_ahead_log.size()?.to_string(),
+        );
+        if let Some(syncs) = log.write_ahead_log.syncs() {
+            metric(
+                "wal_syncs_total",
+                "counter",
+                "Times the write-ahead log was synced to disk.",
+                syncs.to_string(),
+            );
+        }
+        if let Some(duration) = *self.stats.last_recovery.lock().unwrap() {
+            metric(
+                "last_recovery_seconds",
+                "gauge",
+                "Time the last recovery took.",
+                duration.as_secs_f64().to_string(),
+            );
+        }
+        Ok(metrics.join("\n") + "\n")
+    }
+
+    /// Seeds an empty server with the queries read from `reader`, one per line. Unlike executing
+    /// them one by one, the log is written in large unsynced chunks and synced only once at the
+    /// end. Should the load be cut short, recovery restores a prefix of the queries. Read-only
+    /// queries are skipped, ones that only make sense on a connection, like MULTI, are rejected.
+    /// Returns the number of queries loaded.
+    ///
+    /// Other clients are blocked until the load completes. If it fails, the state can be ahead of
+    /// the log, so the server should be restarted.
+    fn load_bulk(&self, reader: impl BufRead) -> Result<usize> {
+        const CHUNK: usize = 10_000;
+
+        let mut log = self.log.lock().unwrap();
+        let mut state = self.state.write().unwrap();
+        if log.transaction_id != 0 {
+            return Err(anyhow!("Bulk loading requires an empty server"));
+        }
+
+        let now = self.clock.now();
+        let mut db = 0;
+        let mut loaded = 0;
+        let mut transactions = vec![];
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let command = Self::parse(&line)
+                .map_err(|e| anyhow!("Line {}: {}", number + 1, e))?
+                .resolve(now);
+            match command {
+                Command::Select { index } => db = index,
+                Command::Restore { .. }
+                | Command::ExpireSweep
+                | Command::Multi
+                | Command::Exec
+                | Command::Discard
+                | Command::Watch { .. }
+                | Command::Unwatch
+                | Command::Subscribe { .. }
+                | Command::Unsubscribe { .. } => {
+                    return Err(anyhow!(
+                        "Line {}: ERR {} can't be bulk loaded",
+                        number + 1,
+                        command.name().to_uppercase()
+                    ));
+                }
+                _ if command.is_mutating() => {
+                    let checked = [(db, command)];
+                    self.check_writable(&checked)
+                        .map_err(|e| anyhow!("Line {}: {}", number + 1, e))?;
+                    let [(_, command)] = checked;
+                    let expired = state.databases[db].expired(&command, now);
+                    let deletes: Vec<Command> = expired
+                        .into_iter()
+                        .map(|key| Command::Delete { key: key.into() })
+                        .collect();
+                    for command in deletes.into_iter().chain([command]) {
+                        state.apply(db, &command);
+                        self.reclaim(state.take_unlinked());
+                        transactions.push(Transaction {
+                            id: log.transaction_id,
+                            db,
+                            command,
+                            timestamp: now,
+                        });
+                        log.transaction_id += 1;
+                    }
+                    loaded += 1;
+                }
+                _ => continue,
+            }
+
+            if transactions.len() >= CHUNK {
+                log.write_ahead_log.append_unsynced(&transactions)?;
+                transactions.clear();
+            }
+        }
+        log.write_ahead_log.append_unsynced(&transactions)?;
+        log.write_ahead_log.flush()?;
+        self.record_evictions(state.evict(now));
+        info!(
+            queries = loaded,
+            transactions = log.transaction_id;
+            "Bulk loaded queries"
+        );
+        Ok(loaded)
+    }
+
+    /// Executes the queries read from `reader`, one per line, as a client would, in a single
+    /// session. Blank lines are skipped. A query that fails stops the script, unless
+    /// `keep_going` is set, in which case it's logged and the script goes on. Returns the number
+    /// of queries executed and how many of them failed.
+    fn exec_script(&self, reader: impl BufRead, keep_going: bool) -> Result<(usize, usize)> {
+        let mut session = Session::default();
+        let (mut executed, mut failed) = (0, 0);
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            executed += 1;
+            let error = match self.execute(&mut session, &line) {
+                Ok(Reply::Error(message)) => anyhow!(message),
+                Ok(_) => continue,
+                Err(e) => e,
+            };
+            if !keep_going {
+                return Err(anyhow!("Line {}: {}", number + 1, error));
+            }
+            failed += 1;
+            warn!(line = number + 1, error:% = error; "Script query failed");
+        }
+        info!(executed = executed, failed = failed; "Executed script");
+        Ok((executed, failed))
+    }
+
+    /// Executes the queries in `reader` as one batch, see `execute_batch`. Returns how many
+    /// queries were executed and how many of those failed.
+    fn exec_script_atomically(&self, reader: impl BufRead) -> Result<(usize, usize)> {
+        let lines = reader.lines().collect::<io::Result<Vec<String>>>()?;
+        let queries: Vec<&str> = lines
+            .iter()
+            .map(String::as_str)
+            .filter(|line| !line.trim().is_empty())
+            .collect();
+        let replies = self.execute_batch(&mut Session::default(), &queries)?;
+        let failed = replies
+            .iter()
+            .filter(|reply| matches!(reply, Reply::Error(_)))
+            .count();
+        info!(executed = replies.len(), failed = failed; "Executed script as one batch");
+        Ok((replies.len(), failed))
+    }
+
+    /// Stops executing commands, waits for the write in progress, if any, and syncs the
+    /// write-ahead log. Then writes a final snapshot, if a path is given.
+    fn shutdown(&self, snapshot_path: Option<&Path>) -> Result<()> {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        {
+            let mut log = self.log.lock().unwrap();
+            log.write_ahead_log.flush()?;
+            info!(transaction_id = log.transaction_id; "Synced write-ahead log");
+        }
+        if let Some(path) = snapshot_path {
+            self.save_snapshot(path)?;
+        }
+        Ok(())
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    fn check_running(&self) -> Result<()> {
+        if self.is_shutting_down() {
+            return Err(anyhow!("ERR server is shutting down"));
+        }
+        Ok(())
+    }
+
+    /// Rewrites the write-ahead log to the minimal set of transactions that reproduce the current
+    /// state, renumbering transaction IDs from 0.
+    fn compact(&self) -> Result<()> {
+        let mut log = self.log.lock().unwrap();
+        let state = self.state.read().unwrap();
+
+        let now = self.clock.now();
+        let mut commands = vec![];
+        for (db, database) in state.databases.iter().enumerate() {
+            for (key, entry) in database.kv.iter().filter(|(_, e)| !e.is_expired(now)) {
+                commands.extend(entry.commands(key).into_iter().map(|c| (db, c)));
+            }
+        }
+        let transactions: Vec<Transaction> = commands
+            .into_iter()
+            .enumerate()
+            .map(|(id, (db, command))| Transaction {
+                id,
+                db,
+                command,
+                timestamp: now,
+            })
+            .collect();
+
+        log.write_ahead_log.rewrite(&transactions)?;
+        info!(
+            from = log.transaction_id,
+            to = transactions.len();
+            "Compacted write-ahead log"
+        );
+        log.transaction_id = transactions.len();
+        Ok(())
+    }
+
+    fn parse(query: &str) -> Result<Command> {
+        Self::parse_tokens(&tokenize(query)?)
+    }
+
+    /// Like Redis, the command name and keywords like COUNT are case-insensitive, keys and values
+    /// aren't.
+    fn parse_tokens(tokens: &[String]) -> Result<Command> {
+        let Some(name) = tokens.first() else {
+            return Err(anyhow!("ERR empty command"));
+        };
+        let name = name.to_ascii_uppercase();
+        let spec = CommandSpec::find(&name)
+            .ok_or_else(|| anyhow!("ERR unknown command '{}'", tokens[0]))?;
+        if !spec.accepts(tokens.len()) {
+            return Err(wrong_arguments(spec.name));
+        }
+        let args: Vec<&str> = tokens[1..].iter().map(String::as_str).collect();
+        (spec.parse)(&args)
+    }
+}
+
+/// Longest string SETRANGE can create, the same as Redis allows.
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+const STRING_TOO_LONG: &str = "ERR string exceeds maximum allowed size";
+
+const NOT_AN_INTEGER: &str = "ERR value is not an integer or out of range";
+
+const OVERFLOW: &str = "ERR increment or decrement would overflow";
+
+fn parse_integer<T: FromStr>(value: &str) -> Result<T> {
+    value.parse().map_err(|_| anyhow!(NOT_AN_INTEGER))
+}
+
+fn read_snapshot(path: impl AsRef<Path>) -> Result<Snapshot> {
+    Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+}
+
+/// Starts the thread dropping the entries UNLINK removed, so freeing large values doesn't hold up
+/// the commands waiting on the state. It stops once the sender is dropped.
+fn spawn_reclaimer() -> Sender<Vec<(String, Entry)>> {
+    let (sender, receiver) = mpsc::channel::<Vec<(String, Entry)>>();
+    thread::spawn(move || receiver.into_iter().for_each(drop));
+    sender
+}
+
+/// Exports the unexpired entries of all databases as a JSON object, mapping database indices to
+/// objects that map keys to their entries. Empty databases are left out.
+fn dump(state: &State, now: u64) -> Result<Reply> {
+    let databases: BTreeMap<usize, BTreeMap<&String, &Entry>> = state
+        .databases
+        .iter()
+        .enumerate()
+        .map(|(db, database)| {
+            let entries: BTreeMap<_, _> = database
+                .kv
+                .iter()
+                .filter(|(_, entry)| !entry.is_expired(now))
+                .collect();
+            (db, entries)
+        })
+        .filter(|(_, entries)| !entries.is_empty())
+        .collect();
+    Ok(Reply::Value(serde_json::to_string(&databases)?))
+}
+
+/// Turns a DUMP export into the commands that replace the current state with it: a FLUSHALL
+/// followed by the commands recreating every entry that hasn't expired since.
+fn restore(dump: &str, now: u64) -> Result<Vec<(usize, Command)>> {
+    let databases: BTreeMap<usize, BTreeMap<String, Entry>> =
+        serde_json::from_str(dump).map_err(|e| anyhow!("ERR invalid dump: {}", e))?;
+    if let Some(db) = databases.keys().find(|db| **db >= DATABASES) {
+        return Err(anyhow!("ERR invalid dump: database {} is out of range", db));
+    }
+
+    let mut commands = vec![(0, Command::FlushAll)];
+    for (db, entries) in &databases {
+        for (key, entry) in entries.iter().filter(|(_, entry)| !entry.is_expired(now)) {
+            commands.extend(entry.commands(key).into_iter().map(|c| (*db, c)));
+        }
+    }
+    Ok(commands)
+}
+
+/// Turns LRANGE style inclusive indices, where negative ones count back from the end, into the
+/// range of positions they cover in a list, or string, of `len` elements.
+fn list_range(len: usize, start: i64, stop: i64) -> std::ops::Range<usize> {
+    let len = len as i64;
+    let start = if start < 0 { start + len } else { start }.max(0);
+    let stop = if stop < 0 { stop + len } else { stop }.min(len - 1);
+    if start > stop {
+        return 0..0;
+    }
+    start as usize..stop as usize + 1
+}
+
+/// Matches `text` against a glob pattern where `*` matches any sequence of characters and `?`
+/// matches exactly one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern, and the text position it's currently matched up to
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some('?') => {
+                p += 1;
+                t += 1;
+            }
+            Some(c) if *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    t = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Splits a query into whitespace separated tokens. Double quotes group text containing
+/// whitespace into a single token, and within them a backslash escapes the next character.
+fn tokenize(query: &str) -> Result<Vec<String>> {
+    let mut tokens = vec![];
+    let mut chars = query.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Ok(tokens);
+        }
+
+        let mut token = String::new();
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+            if c != '"' {
+                token.push(c);
+                continue;
+            }
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(escaped) => token.push(escaped),
+                        None => return Err(anyhow!("ERR unbalanced quotes")),
+                    },
+                    Some(c) => token.push(c),
+                    None => return Err(anyhow!("ERR unbalanced quotes")),
+                }
+            }
+        }
+        tokens.push(token);
+    }
+}
+
+const MAX_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_WAL_PATH: &str = "wal";
+/// Where the write-ahead log was kept before it was split into segments.
+const LEGACY_WAL_PATH: &str = "wal.txt";
+const ENCRYPTION_KEY_VAR: &str = "KEYVAL_ENCRYPTION_KEY";
+const REPLICA_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the write-ahead log gets to catch up on time based syncs, see `WriteAheadLog::tick`.
+const SYNC_TICK_INTERVAL: Duration = Duration::from_millis(100);
+/// How often recovery logs its progress, unless `Server::with_recovery_progress` reports it.
+const RECOVERY_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the checkpointer checks whether a checkpoint is due.
+const CHECKPOINT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often `--follow` checks the write-ahead log for new transactions.
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Most expired keys deleted by a single sweep.
+const EXPIRE_BATCH: usize = 1000;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:6380")]
+    listen: String,
+    /// Wire protocol, 'resp' or 'line'
+    #[arg(long, default_value = "resp")]
+    protocol: Protocol,
+    /// Address to serve Prometheus metrics on, at /metrics
+    #[arg(long)]
+    metrics_listen: Option<String>,
+    /// Close connections that send nothing for this many seconds, 0 to keep them open. Subscribed
+    /// connections can stay idle, but not while halfway through a request.
+    #[arg(long, default_value_t = 0)]
+    timeout_secs: u64,
+    /// Close connections sending a request larger than this many bytes
+    #[arg(long, default_value_t = 1024 * 1024 * 1024)]
+    max_request_bytes: usize,
+    /// Path of the write-ahead log, segments are stored as <path>.00001 and so on. A log from
+    /// before segments, at <path> itself or at wal.txt for the default path, becomes the first
+    /// segment
+    #[arg(long, default_value = DEFAULT_WAL_PATH)]
+    wal_path: PathBuf,
+    /// Path of the snapshot recovery starts from, rewritten at every startup
+    #[arg(long, default_value = "snapshot.json")]
+    snapshot_path: PathBuf,
+    /// Snapshot and drop the write-ahead log segments it holds after this many transactions, so
+    /// restarts stay fast. Needs snapshots, which an encrypted log goes without
+    #[arg(long)]
+    checkpoint_every_transactions: Option<usize>,
+    /// Snapshot and drop the write-ahead log segments it holds this many seconds after the last
+    /// time, if anything was logged since
+    #[arg(long)]
+    checkpoint_every_secs: Option<u64>,
+    /// Keep the write-ahead log in memory, so nothing survives a restart
+    #[arg(long, conflicts_with_all = ["wal_path", "snapshot_path"])]
+    in_memory: bool,
+    /// Recover only up to and including this transaction, moving the write-ahead log segments
+    /// aside as <segment>.discarded and keeping only the transactions up to it
+    #[arg(
+        long,
+        conflicts_with_all = ["replica_of", "recover_to_timestamp_ms", "dry_run"]
+    )]
+    recover_to_id: Option<usize>,
+    /// Recover only the transactions logged up to this Unix timestamp in milliseconds, moving
+    /// the write-ahead log segments aside like --recover-to-id
+    #[arg(long, conflicts_with_all = ["replica_of", "dry_run"])]
+    recover_to_timestamp_ms: Option<u64>,
+    /// Seed the store with the queries in this file, one per line
+    #[arg(long)]
+    load: Option<PathBuf>,
+    /// Execute the queries in this file, one per line, once recovered. Unlike --load, they're
+    /// executed and logged one by one, the same as a client sending them
+    #[arg(long)]
+    exec_file: Option<PathBuf>,
+    /// Keep executing --exec-file after a query fails, rather than exit
+    #[arg(long, requires = "exec_file")]
+    exec_keep_going: bool,
+    /// Execute all of --exec-file as one batch, logged as a single transaction, so either all of
+    /// it is recovered or none of it. Queries that fail don't stop the others
+    #[arg(long, requires = "exec_file", conflicts_with = "exec_keep_going")]
+    exec_atomically: bool,
+    /// Rewrite the write-ahead log to the fewest transactions that reproduce the state, once
+    /// recovered. Renumbers the transactions, so the snapshot is moved aside as
+    /// <snapshot>.discarded first and a new one is taken afterwards
+    #[arg(long, conflicts_with_all = ["replica_of", "dry_run"])]
+    compact: bool,
+    /// Run as a read-only replica following the write-ahead log of a primary at this path
+    #[arg(
+        long,
+        conflicts_with_all = ["wal_path", "snapshot_path", "in_memory", "load", "exec_file"]
+    )]
+    replica_of: Option<PathBuf>,
+    /// File holding the key to encrypt the write-ahead log with, as 64 hexadecimal digits. The
+    /// key can also be set through the KEYVAL_ENCRYPTION_KEY environment variable.
+    #[arg(long, conflicts_with = "in_memory")]
+    encryption_key_file: Option<PathBuf>,
+    /// Record format of the write-ahead log, 'json' or 'binary'. Has to match the format the log
+    /// was written in. Encrypted logs are always binary
+    #[arg(long, default_value = "json", conflicts_with = "in_memory")]
+    wal_format: WalFormat,
+    /// Evict the least recently used keys beyond this many, for use as a cache
+    #[arg(long)]
+    max_keys: Option<usize>,
+    /// Evict the least recently used keys beyond about this many bytes of keys and values
+    #[arg(long)]
+    max_bytes: Option<usize>,
+    /// Reject writes of keys longer than this many bytes, 0 for no limit
+    #[arg(long)]
+    max_key_bytes: Option<usize>,
+    /// Reject writes of values longer than this many bytes, 0 for no limit
+    #[arg(long)]
+    max_value_bytes: Option<usize>,
+    /// Answer writes with the replies they would get, without logging or applying them
+    #[arg(long, conflicts_with_all = ["replica_of", "load"])]
+    dry_run: bool,
+    /// Track when keys were last accessed, for OBJECT IDLETIME
+    #[arg(long)]
+    track_idle_times: bool,
+    /// Log recovery progress every this many transactions replayed, rather than every 5 seconds
+    #[arg(long)]
+    recovery_progress_every: Option<usize>,
+    /// Seed the random number generator, so RANDOMKEY picks the same keys every run
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Publish an event to the __keyspace@<db>__:<key> channel whenever a key changes
+    #[arg(long)]
+    keyspace_notifications: bool,
+    /// When to sync the write-ahead log to disk: after every write with 'always', at most once
+    /// every N seconds with a number N, or leaving it to the OS with 'never'
+    #[arg(long, default_value = "always")]
+    durability: Durability,
+    /// Bytes of write-ahead log records to buffer before writing them to the file. With
+    /// --durability always every write is synced, so this only helps batches as large as --load
+    /// writes
+    #[arg(long, default_value_t = 0)]
+    wal_buffer_size: usize,
+    /// Start a new write-ahead log segment once the last one has grown to this many bytes
+    #[arg(long, default_value_t = MAX_SEGMENT_SIZE)]
+    max_segment_size: u64,
+    /// Gzip write-ahead log segments once they're sealed, that is, once the log moved on to the
+    /// next one
+    #[arg(long, conflicts_with = "in_memory")]
+    compress_segments: bool,
+    /// Log a warning for commands holding up others for at least this many milliseconds, 0 to
+    /// never
+    #[arg(long, default_value_t = 100)]
+    slow_command_ms: u64,
+    /// Milliseconds between sweeps deleting expired keys, 0 to only delete them when they're
+    /// accessed
+    #[arg(long, default_value_t = 100)]
+    expire_interval_ms: u64,
+    /// Check the write-ahead log for corruption and gaps in the transaction IDs, and exit
+    #[arg(long, conflicts_with_all = ["in_memory", "replica_of", "load"])]
+    verify: bool,
+    /// Print the transactions in the write-ahead log, then keep printing new ones as they're
+    /// appended, like `tail -f`. Never writes to the log, so a server can keep appending to it
+    #[arg(long, conflicts_with_all = ["in_memory", "replica_of", "load", "verify"])]
+    follow: bool,
+    /// Recover from the snapshot and the write-ahead log, print how many keys that gives, and
+    /// exit. Never writes to either
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "in_memory",
+            "replica_of",
+            "load",
+            "exec_file",
+            "verify",
+            "follow",
+            "recover_to_id",
+            "recover_to_timestamp_ms",
+        ]
+    )]
+    replay_only: bool,
+}
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_default_env()
+        .format_file(true)
+        .format_line_number(true)
+        .format_target(false)
+        .init();
+
+    let args = Args::parse();
+    if args.in_memory {
+        let w = InMemoryWriteAheadLog::new().without_history();
+        return run(Server::new(w), &args, None);
+    }
+    match (encryption_key(&args)?, args.wal_format) {
+        // Snapshots aren't encrypted, so they're skipped when the log is
+        (Some(key), _) => run_on_disk(&args, EncryptedCodec::new(&key), false),
+        (None, WalFormat::Json) => run_on_disk(&args, JsonCodec, true),
+        (None, WalFormat::Binary) => run_on_disk(&args, BinaryCodec, true),
+    }
+}
+
+/// Record format of the on-disk write-ahead log, see `RecordCodec`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum WalFormat {
+    Json,
+    Binary,
+}
+
+impl FromStr for WalFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(WalFormat::Json),
+            "binary" => Ok(WalFormat::Binary),
+            _ => Err(anyhow!(
+                "Unknown write-ahead log format '{}', expected 'json' or 'binary'",
+                s
+            )),
+        }
+    }
+}
+
+/// Reads the key from the file `--encryption-key-file` points to, or from the
+/// `KEYVAL_ENCRYPTION_KEY` environment variable. Never from the command line itself, which other
+/// users can see in the process list.
+fn encryption_key(args: &Args) -> Result<Option<[u8; KEY_SIZE]>> {
+    let hex = match (&args.encryption_key_file, std::env::var(ENCRYPTION_KEY_VAR)) {
+        (Some(path), _) => fs::read_to_string(path)?,
+        (None, Ok(hex)) => hex,
+        (None, Err(_)) => return Ok(None),
+    };
+    EncryptedCodec::parse_key(&hex).map(Some)
+}
+
+fn run_on_disk<C>(args: &Args, codec: C, snapshots: bool) -> Result<()>
+where
+    C: RecordCodec + Send + 'static,
+{
+    if args.verify {
+        return verify(&args.wal_path, codec);
+    }
+    if args.follow {
+        return follow(&args.wal_path, codec);
+    }
+    if args.replay_only {
+        let snapshot_path = snapshots.then_some(args.snapshot_path.as_path());
+        return replay_only(
+            &args.wal_path,
+            codec,
+            snapshot_path,
+            &mut io::stdout().lock(),
+        );
+    }
+    if let Some(path) = &args.replica_of {
+        let w = OnDiskWriteAheadLog::open_read_only(path, codec)?;
+        return run(Server::new(w).into_replica(), args, None);
+    }
+    let snapshot_path = snapshots.then_some(args.snapshot_path.as_path());
+    if args.wal_path == Path::new(DEFAULT_WAL_PATH) {
+        migrate_legacy_log(Path::new(LEGACY_WAL_PATH), &args.wal_path)?;
+    }
+    let mut w = OnDiskWriteAheadLog::with_codec(&args.wal_path, args.durability, codec)?
+        .with_max_segment_size(args.max_segment_size)
+        .with_write_buffer(args.wal_buffer_size)?;
+    if args.compress_segments {
+        w = w.with_compressed_segments();
+    }
+    run(Server::new(w), args, snapshot_path)
+}
+
+/// Prints a summary of the write-ahead log at `path`, failing if anything's wrong with it.
+fn verify<C: RecordCodec>(path: &Path, codec: C) -> Result<()> {
+    let verification = OnDiskWriteAheadLog::open_read_only(path, codec)?.verify()?;
+    println!(
+        "{} records in {} segments",
+        verification.records, verification.segments
+    );
+    if let (Some(first), Some(last)) = (verification.first_id, verification.last_id) {
+        println!("Transactions {} to {}", first, last);
+    }
+    for problem in &verification.problems {
+        println!("{}", problem);
+    }
+    if !verification.problems.is_empty() {
+        return Err(anyhow!(
+            "Found {} problems in the write-ahead log",
+            verification.problems.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Prints the transactions of the write-ahead log at `path` as they're appended, until stopped.
+fn follow<C: RecordCodec>(path: &Path, codec: C) -> Result<()> {
+    let mut log = OnDiskWriteAheadLog::open_read_only(path, codec)?;
+    let mut out = io::stdout().lock();
+    let mut next_id = 0;
+    loop {
+        next_id = print_transactions(&mut log, next_id, &mut out)?;
+        out.flush()?;
+        thread::sleep(FOLLOW_POLL_INTERVAL);
+    }
+}
 
-    println!("{:?}", s);
+/// Prints the transactions from `start_id` on, one per line, returning the ID of the next one to
+/// be appended. A record still being appended is left for the next call.
+fn print_transactions<W: WriteAheadLog>(
+    log: &mut W,
+    start_id: usize,
+    out: &mut impl Write,
+) -> Result<usize> {
+    let mut next_id = start_id;
+    for result in log.replay_from(start_id)? {
+        let transaction = result?;
+        writeln!(
+            out,
+            "{}\tdb {}\t{:?}",
+            transaction.id, transaction.db, transaction.command
+        )?;
+        next_id = transaction.id + 1;
+    }
+    Ok(next_id)
+}
 
-    s.recover()?;
+/// Recovers the way startup does, then prints a summary of the result to `out` rather than
+/// serving it.
+fn replay_only<C: RecordCodec>(
+    path: &Path,
+    codec: C,
+    snapshot_path: Option<&Path>,
+    out: &mut impl Write,
+) -> Result<()> {
+    let server = Server::new(OnDiskWriteAheadLog::open_read_only(path, codec)?);
+    if let Some(path) = snapshot_path.filter(|path| path.exists()) {
+        server.load_snapshot(path)?;
+    }
+    server.recover()?;
+    print_summary(&server, out)
+}
 
+fn print_summary<W: WriteAheadLog>(server: &Server<W>, out: &mut impl Write) -> Result<()> {
+    let log = server.log.lock().unwrap();
+    let state = server.state.read().unwrap();
+    writeln!(out, "{} keys", state.len())?;
+    match log.transaction_id.checked_sub(1) {
+        Some(id) => writeln!(out, "Last transaction {}", id)?,
+        None => writeln!(out, "No transactions")?,
+    }
+    if let Some(duration) = *server.stats.last_recovery.lock().unwrap() {
+        writeln!(out, "Recovered in {} ms", duration.as_millis())?;
+    }
     Ok(())
 }
+
+fn run<W>(server: Server<W>, args: &Args, snapshot_path: Option<&Path>) -> Result<()>
+where
+    W: WriteAheadLog + Send + 'static,
+{
+    let mut server = server
+        .with_limits(Limits {
+            max_keys: args.max_keys,
+            max_bytes: args.max_bytes,
+        })
+        .with_max_sizes(MaxSizes {
+            key_bytes: args.max_key_bytes,
+            value_bytes: args.max_value_bytes,
+        });
+    if args.keyspace_notifications {
+        server = server.with_keyspace_notifications();
+    }
+    if args.track_idle_times {
+        server = server.with_idle_times();
+    }
+    if let Some(seed) = args.seed {
+        server = server.with_seed(seed);
+    }
+    if let Some(every) = args.recovery_progress_every {
+        server = server.with_recovery_progress(every, RecoveryProgress::log);
+    }
+    if args.dry_run {
+        server = server.with_dry_run();
+    }
+    if args.slow_command_ms > 0 {
+        server = server.with_slow_command_threshold(Duration::from_millis(args.slow_command_ms));
+    }
+
+    // Clients can connect, and PING, while the server recovers
+    let server = Arc::new(server);
+    server.recovering.store(true, Ordering::SeqCst);
+    let listener = TcpListener::bind(&args.listen)?;
+    let limits = ConnectionLimits {
+        read_timeout: (args.timeout_secs > 0).then(|| Duration::from_secs(args.timeout_secs)),
+        max_request_bytes: args.max_request_bytes,
+    };
+    let serving = {
+        let (server, protocol) = (Arc::clone(&server), args.protocol);
+        thread::spawn(move || net::serve(server, listener, protocol, limits))
+    };
+
+    let target = match (args.recover_to_id, args.recover_to_timestamp_ms) {
+        (Some(id), _) => RecoverTarget::Id(id),
+        (_, Some(timestamp)) => RecoverTarget::Timestamp(timestamp),
+        _ => RecoverTarget::End,
+    };
+    if let Some(path) = snapshot_path.filter(|path| path.exists()) {
+        let snapshot = read_snapshot(path)?;
+        if target.admits(snapshot.transaction_id) {
+            server.restore_snapshot(snapshot)?;
+        } else {
+            // Fails the recovery if the log no longer starts at the first transaction
+            warn!(
+                transaction_id = snapshot.transaction_id;
+                "Replaying the whole write-ahead log, the snapshot may be past the recovery target"
+            );
+        }
+    }
+    server.recover_until(target)?;
+    if let Some(path) = &args.load {
+        server.load_bulk(BufReader::new(File::open(path)?))?;
+    }
+    if let Some(path) = &args.exec_file {
+        let reader = BufReader::new(File::open(path)?);
+        if args.exec_atomically {
+            server.exec_script_atomically(reader)?;
+        } else {
+            server.exec_script(reader, args.exec_keep_going)?;
+        }
+    }
+    if args.compact {
+        // The old snapshot doesn't match the renumbered log, and can't be replaced by a new one
+        // at the same time the log is
+        if let Some(path) = snapshot_path.filter(|path| path.exists()) {
+            let mut aside = path.as_os_str().to_owned();
+            aside.push(".discarded");
+            fs::rename(path, aside)?;
+        }
+        server.compact()?;
+    }
+    if let Some(path) = snapshot_path {
+        // Next startup only has to replay what gets logged from here on
+        server.save_snapshot(path)?;
+    }
+    server.recovering.store(false, Ordering::SeqCst);
+
+    if server.replica {
+        let replica = Arc::clone(&server);
+        thread::spawn(move || loop {
+            thread::sleep(REPLICA_POLL_INTERVAL);
+            if let Err(e) = replica.recover() {
+                warn!(error:% = format!("{:#}", e); "Failed to catch up with the primary");
+            }
+        });
+    } else {
+        Server::spawn_ticker(&server);
+        if args.expire_interval_ms > 0 {
+            Server::spawn_expirer(&server, Duration::from_millis(args.expire_interval_ms));
+        }
+    }
+    let policy = CheckpointPolicy {
+        every_transactions: args.checkpoint_every_transactions,
+        every_ms: args
+            .checkpoint_every_secs
+            .map(|secs| secs.saturating_mul(1000)),
+    };
+    if let Some(path) = snapshot_path.filter(|_| policy != CheckpointPolicy::default()) {
+        Server::spawn_checkpointer(&server, path.to_path_buf(), policy);
+    }
+    if let Some(address) = &args.metrics_listen {
+        metrics::spawn(Arc::clone(&server), address)?;
+    }
+    let handler_server = Arc::clone(&server);
+    let snapshot_path = snapshot_path.map(Path::to_path_buf);
+    // Runs on its own thread on SIGINT or SIGTERM
+    ctrlc::set_handler(move || {
+        info!("Shutting down");
+        match handler_server.shutdown(snapshot_path.as_deref()) {
+            Ok(()) => std::process::exit(0),
+            Err(e) => {
+                error!(error:% = e; "Failed to shut down cleanly");
+                std::process::exit(1)
+            }
+        }
+    })?;
+
+    serving
+        .join()
+        .unwrap_or_else(|_| Err(anyhow!("Stopped serving clients")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> Server<InMemoryWriteAheadLog> {
+        Server::new(InMemoryWriteAheadLog::new())
+    }
+
+    fn run<W: WriteAheadLog>(server: &Server<W>, session: &mut Session, query: &str) -> Reply {
+        server
+            .execute(session, query)
+            .unwrap_or_else(|e| Reply::Error(e.to_string()))
+    }
+
+    /// An empty directory of its own for a test's files.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("keyval-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A fresh server recovered from the log of `server`.
+    fn recovered(server: Server<InMemoryWriteAheadLog>) -> Server<InMemoryWriteAheadLog> {
+        let recovered = Server::new(server.log.into_inner().unwrap().write_ahead_log);
+        recovered.recover().unwrap();
+        recovered
+    }
+
+    fn set(id: usize, key: &str, value: &str) -> Transaction {
+        Transaction {
+            id,
+            db: 0,
+            command: Command::Set {
+                key: key.into(),
+                value: value.into(),
+            },
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn tick_syncs_buffered_records_once_due() {
+        let dir = temp_dir("tick");
+        let path = dir.join("wal");
+        let clock = Arc::new(clock::MockClock::new(0));
+        let mut log = OnDiskWriteAheadLog::new(&path, Durability::EverySecs(1))
+            .unwrap()
+            .with_clock(clock.clone())
+            .with_write_buffer(1 << 16)
+            .unwrap();
+        let on_disk = || fs::metadata(segment_path(&path, 1)).unwrap().len();
+        let empty = on_disk();
+        log.append(&set(1, "a", "1")).unwrap();
+        assert_eq!(on_disk(), empty);
+        log.tick().unwrap();
+        assert!(on_disk() > empty, "written out even before a sync is due");
+        assert!(!log.synced);
+        clock.advance(Duration::from_secs(1));
+        log.tick().unwrap();
+        assert!(log.synced);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn records_span_segments_and_replay_in_order() {
+        let dir = temp_dir("segments");
+        let path = dir.join("wal");
+        let mut log = OnDiskWriteAheadLog::new(&path, Durability::Never)
+            .unwrap()
+            .with_max_segment_size(64);
+        for id in 1..=10 {
+            log.append(&set(id, "key", "value")).unwrap();
+        }
+        assert!(segments(&path).unwrap().len() > 1);
+        let ids: Vec<usize> = OnDiskWriteAheadLog::new(&path, Durability::Never)
+            .unwrap()
+            .replay()
+            .unwrap()
+            .map(|transaction| transaction.unwrap().id)
+            .collect();
+        assert_eq!(ids, (1..=10).collect::<Vec<_>>());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compressed_segments_replay_in_order() {
+        let dir = temp_dir("compressed");
+        let path = dir.join("wal");
+        let open = || {
+            OnDiskWriteAheadLog::new(&path, Durability::Never)
+                .unwrap()
+                .with_max_segment_size(64)
+                .with_compressed_segments()
+        };
+        let mut log = open();
+        for id in 0..10 {
+            log.append(&set(id, "key", "value")).unwrap();
+        }
+        let segments = segments(&path).unwrap();
+        assert!(segments.len() > 2);
+        let (last, sealed) = segments.split_last().unwrap();
+        assert!(sealed.iter().all(|(_, path)| is_compressed(path)));
+        assert!(!is_compressed(&last.1));
+        let ids: Vec<usize> = open()
+            .replay()
+            .unwrap()
+            .map(|transaction| transaction.unwrap().id)
+            .collect();
+        assert_eq!(ids, (0..10).collect::<Vec<_>>());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replay_from_seeks_past_indexed_records() {
+        let dir = temp_dir("index");
+        let path = dir.join("wal");
+        let mut log = OnDiskWriteAheadLog::new(&path, Durability::Never).unwrap();
+        for id in 0..3 * INDEX_INTERVAL {
+            log.append(&set(id, "key", "value")).unwrap();
+        }
+        // Break a record early on, which only a replay reading it notices
+        let segment = segment_path(&path, 1);
+        let contents = fs::read_to_string(&segment).unwrap();
+        fs::write(&segment, contents.replacen("value", "VALUE", 10)).unwrap();
+
+        let start_id = 2 * INDEX_INTERVAL + 1;
+        let ids = log
+            .replay_from(start_id)
+            .unwrap()
+            .map(|transaction| transaction.map(|t| t.id))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(ids, (start_id..3 * INDEX_INTERVAL).collect::<Vec<_>>());
+        assert!(log
+            .replay()
+            .unwrap()
+            .any(|transaction| transaction.is_err()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn recovery_reports_progress_through_the_callback() {
+        let dir = temp_dir("progress");
+        let path = dir.join("wal");
+        let mut log = OnDiskWriteAheadLog::new(&path, Durability::Never).unwrap();
+        for id in 0..100 {
+            log.append(&set(id, "key", "value")).unwrap();
+        }
+        let reports = Arc::new(Mutex::new(vec![]));
+        let server = {
+            let reports = Arc::clone(&reports);
+            Server::new(log)
+                .with_recovery_progress(10, move |progress| reports.lock().unwrap().push(*progress))
+        };
+        server.recover().unwrap();
+        let reports = reports.lock().unwrap();
+        let records: Vec<usize> = reports.iter().map(|progress| progress.records).collect();
+        assert_eq!(records, (1..=10).map(|n| n * 10).collect::<Vec<_>>());
+        assert!(reports.windows(2).all(|w| w[0].bytes < w[1].bytes));
+        let total = fs::metadata(segment_path(&path, 1)).unwrap().len();
+        assert!(reports.iter().all(|progress| progress.total_bytes == total));
+        assert_eq!(reports.last().unwrap().percent(), Some(100.0));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_segment_version_is_refused() {
+        let dir = temp_dir("version");
+        let path = dir.join("wal");
+        let mut segment = [SEGMENT_MAGIC, &[SEGMENT_VERSION + 1, b'\n']].concat();
+        segment.extend(JsonCodec.encode(&set(0, "a", "1")).unwrap());
+        fs::write(segment_path(&path, 1), segment).unwrap();
+
+        let server = Server::new(OnDiskWriteAheadLog::new(&path, Durability::Never).unwrap());
+        let error = format!("{:#}", server.recover().unwrap_err());
+        let expected = format!(
+            "Write-ahead log format version {} is unknown, this server reads up to version {}",
+            SEGMENT_VERSION + 1,
+            SEGMENT_VERSION
+        );
+        assert!(error.contains(&expected), "{}", error);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn legacy_log_becomes_the_first_segment() {
+        let dir = temp_dir("legacy");
+        let path = dir.join("wal.txt");
+        let records: Vec<u8> = [set(1, "a", "1"), set(2, "b", "2")]
+            .iter()
+            .flat_map(|transaction| JsonCodec.encode(transaction).unwrap())
+            .collect();
+        fs::write(&path, records).unwrap();
+        let mut log = OnDiskWriteAheadLog::new(&path, Durability::Never).unwrap();
+        log.append(&set(3, "c", "3")).unwrap();
+        let ids: Vec<usize> = log.replay().unwrap().map(|t| t.unwrap().id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert!(!path.exists());
+
+        fs::write(&path, "").unwrap();
+        assert!(OnDiskWriteAheadLog::new(&path, Durability::Never).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn evicts_least_recently_used_first() {
+        let server = server().with_limits(Limits {
+            max_keys: Some(2),
+            max_bytes: None,
+        });
+        let mut session = Session::default();
+        run(&server, &mut session, "SET a 1");
+        run(&server, &mut session, "SET b 2");
+        run(&server, &mut session, "GET a");
+        run(&server, &mut session, "SET c 3");
+        assert_eq!(run(&server, &mut session, "GET b"), Reply::Nil);
+        run(&server, &mut session, "SET d 4");
+        assert_eq!(run(&server, &mut session, "GET a"), Reply::Nil);
+        assert_eq!(
+            run(&server, &mut session, "GET c"),
+            Reply::Value("3".into())
+        );
+        assert_eq!(
+            run(&server, &mut session, "GET d"),
+            Reply::Value("4".into())
+        );
+        assert_eq!(server.stats.evicted.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn evicts_to_stay_within_the_byte_budget() {
+        let server = server().with_limits(Limits {
+            max_keys: None,
+            max_bytes: Some(10),
+        });
+        let mut session = Session::default();
+        run(&server, &mut session, "SET a 1234");
+        run(&server, &mut session, "SET b 1234");
+        assert_eq!(server.state.read().unwrap().bytes, 10);
+        run(&server, &mut session, "SET c 12");
+        assert_eq!(run(&server, &mut session, "GET a"), Reply::Nil);
+        assert_eq!(server.state.read().unwrap().bytes, 8);
+        run(&server, &mut session, "SET b 123456789");
+        let state = server.state.read().unwrap();
+        assert!(state.bytes <= 10);
+        assert_eq!(state.len(), 1, "b alone is 10 bytes");
+        assert_eq!(state.lru.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn recovers_up_to_an_id_or_a_timestamp() {
+        let recover = |target| {
+            let mut log = InMemoryWriteAheadLog::new();
+            for (id, value) in ["1", "2", "3"].iter().enumerate() {
+                let transaction = Transaction {
+                    timestamp: id as u64 * 10,
+                    ..set(id, "a", value)
+                };
+                log.append(&transaction).unwrap();
+            }
+            let server = Server::new(log);
+            server.recover_until(target).unwrap();
+            let mut session = Session::default();
+            let value = run(&server, &mut session, "GET a");
+            let kept = server.log.lock().unwrap().write_ahead_log.data.len();
+            (value, run(&server, &mut session, "LASTID"), kept)
+        };
+        let value = |value: &str| Reply::Value(value.into());
+        assert_eq!(
+            recover(RecoverTarget::Id(1)),
+            (value("2"), Reply::Int(1), 2)
+        );
+        assert_eq!(
+            recover(RecoverTarget::Timestamp(5)),
+            (value("1"), Reply::Int(0), 1)
+        );
+        assert_eq!(recover(RecoverTarget::End), (value("3"), Reply::Int(2), 3));
+    }
+
+    #[test]
+    fn recovering_to_an_id_moves_later_segments_aside() {
+        let dir = temp_dir("pitr");
+        let (path, snapshot_path) = (dir.join("wal"), dir.join("snapshot.json"));
+        let open = || Server::new(OnDiskWriteAheadLog::new(&path, Durability::Never).unwrap());
+        let server = open();
+        let mut session = Session::default();
+        run(&server, &mut session, "SET a 1");
+        run(&server, &mut session, "SET a 2");
+        server.save_snapshot(&snapshot_path).unwrap();
+        drop(server);
+
+        let target = RecoverTarget::Id(0);
+        let snapshot = read_snapshot(&snapshot_path).unwrap();
+        assert!(!target.admits(snapshot.transaction_id));
+        let server = open();
+        server.recover_until(target).unwrap();
+        assert_eq!(
+            run(&server, &mut session, "GET a"),
+            Reply::Value("1".into())
+        );
+        drop(server);
+        assert!(dir.join("wal.00001.discarded").exists());
+
+        let server = open();
+        server.recover().unwrap();
+        assert_eq!(
+            run(&server, &mut session, "GET a"),
+            Reply::Value("1".into())
+        );
+        assert_eq!(run(&server, &mut session, "LASTID"), Reply::Int(0));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn snapshot_must_meet_the_log_without_a_gap() {
+        let dir = temp_dir("snapshot-gap");
+        let snapshot_path = dir.join("snapshot.json");
+        let server = server();
+        let mut session = Session::default();
+        for key in ["a", "b", "c"] {
+            run(&server, &mut session, &format!("SET {} 1", key));
+        }
+        assert_eq!(server.save_snapshot(&snapshot_path).unwrap(), 3);
+
+        let recover = |ids: &[usize]| {
+            let mut log = InMemoryWriteAheadLog::new();
+            for id in ids {
+                log.append(&set(*id, "d", "1")).unwrap();
+            }
+            let server = Server::new(log);
+            server
+                .restore_snapshot(read_snapshot(&snapshot_path).unwrap())
+                .unwrap();
+            server.recover().map(|_| ()).map_err(|e| e.to_string())
+        };
+        assert_eq!(recover(&[0, 1, 2, 3, 4]), Ok(()));
+        assert_eq!(
+            recover(&[0, 1, 2, 5, 6]),
+            Err("Unexpected transaction ID: got 5, expected 3".into())
+        );
+        assert_eq!(
+            recover(&[4, 5]),
+            Err("Unexpected transaction ID: got 4, expected 3".into())
+        );
+        for ids in [&[][..], &[0, 1]] {
+            assert_eq!(
+                recover(ids),
+                Err("Write-ahead log ends before transaction 2, which was already applied".into())
+            );
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn checkpoints_after_every_n_transactions() {
+        let dir = temp_dir("checkpoint");
+        let snapshot_path = dir.join("snapshot.json");
+        let server = Arc::new(server());
+        let policy = CheckpointPolicy {
+            every_transactions: Some(5),
+            every_ms: None,
+        };
+        let checkpointer = Server::spawn_checkpointer(&server, snapshot_path.clone(), policy);
+        // Counts from where the log is once the thread runs
+        thread::sleep(CHECKPOINT_POLL_INTERVAL * 2);
+        let mut session = Session::default();
+        for i in 0..4 {
+            run(&server, &mut session, &format!("SET k{} v", i));
+        }
+        thread::sleep(CHECKPOINT_POLL_INTERVAL * 3);
+        assert!(!snapshot_path.exists());
+
+        run(&server, &mut session, "SET k4 v");
+        let started = Instant::now();
+        while !snapshot_path.exists() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(CHECKPOINT_POLL_INTERVAL);
+        }
+        server.shutdown(None).unwrap();
+        checkpointer.join().unwrap();
+        let snapshot = read_snapshot(&snapshot_path).unwrap();
+        assert_eq!(snapshot.transaction_id, 5);
+        let log = &server.log.lock().unwrap().write_ahead_log;
+        let ids: Vec<usize> = log.data.iter().map(|t| t.id).collect();
+        assert_eq!(
+            ids,
+            vec![4],
+            "keeps only the last transaction in the snapshot"
+        );
+
+        let recovered = Server::new(InMemoryWriteAheadLog {
+            data: log.data.clone(),
+            history: true,
+        });
+        recovered.restore_snapshot(snapshot).unwrap();
+        recovered.recover().unwrap();
+        assert_eq!(run(&recovered, &mut session, "DBSIZE"), Reply::Int(5));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_discard_is_finished_on_open() {
+        let dir = temp_dir("discard");
+        let path = dir.join("wal");
+        let segment = |transactions: &[Transaction]| {
+            let records = transactions
+                .iter()
+                .flat_map(|transaction| JsonCodec.encode(transaction).unwrap());
+            [segment_header(), records.collect()].concat()
+        };
+        let (first, second) = (set(0, "a", "1"), set(1, "a", "2"));
+        fs::write(segment_path(&path, 1), segment(&[first.clone(), second])).unwrap();
+        fs::write(segment_path(&path, 2), segment(&[first])).unwrap();
+        let mut log = OnDiskWriteAheadLog::new(&path, Durability::Never).unwrap();
+        let ids: Vec<usize> = log.replay().unwrap().map(|t| t.unwrap().id).collect();
+        assert_eq!(ids, vec![0]);
+        assert!(dir.join("wal.00001.discarded").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compacting_keeps_the_last_write() {
+        let server = server();
+        let mut session = Session::default();
+        for i in 0..1000 {
+            run(&server, &mut session, &format!("SET a {}", i));
+        }
+        server.compact().unwrap();
+        let log = server.log.lock().unwrap();
+        assert_eq!(log.transaction_id, 1);
+        assert_eq!(log.write_ahead_log.data.len(), 1);
+        assert_eq!(
+            log.write_ahead_log.data[0].command,
+            Command::Set {
+                key: "a".into(),
+                value: "999".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn torn_last_record_is_truncated() {
+        let dir = temp_dir("torn");
+        let path = dir.join("wal");
+        let mut log = OnDiskWriteAheadLog::new(&path, Durability::Never).unwrap();
+        log.append(&set(0, "a", "1")).unwrap();
+        log.append(&set(1, "a", "2")).unwrap();
+        drop(log);
+        let segment = segment_path(&path, 1);
+        let len = fs::metadata(&segment).unwrap().len();
+        let torn = JsonCodec.encode(&set(2, "a", "3")).unwrap();
+        let mut file = File::options().append(true).open(&segment).unwrap();
+        file.write_all(&torn[..torn.len() / 2]).unwrap();
+        drop(file);
+
+        let server = Server::new(OnDiskWriteAheadLog::new(&path, Durability::Never).unwrap());
+        server.recover().unwrap();
+        let mut session = Session::default();
+        assert_eq!(
+            run(&server, &mut session, "GET a"),
+            Reply::Value("2".into())
+        );
+        assert_eq!(fs::metadata(&segment).unwrap().len(), len);
+        run(&server, &mut session, "SET a 4");
+        let mut log = server.log.into_inner().unwrap().write_ahead_log;
+        let ids: Vec<usize> = log.replay().unwrap().map(|t| t.unwrap().id).collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_record_in_the_middle_fails_recovery() {
+        let dir = temp_dir("corrupt");
+        let path = dir.join("wal");
+        let mut log = OnDiskWriteAheadLog::new(&path, Durability::Never).unwrap();
+        for (id, value) in ["1", "2", "3"].iter().enumerate() {
+            log.append(&set(id, "a", value)).unwrap();
+        }
+        drop(log);
+        let segment = segment_path(&path, 1);
+        let contents = fs::read_to_string(&segment).unwrap();
+        fs::write(&segment, contents.replacen("\"2\"", "\"2", 1)).unwrap();
+        let len = fs::metadata(&segment).unwrap().len();
+
+        let server = Server::new(OnDiskWriteAheadLog::new(&path, Durability::Never).unwrap());
+        let error = format!("{:#}", server.recover().unwrap_err());
+        assert!(error.contains("record 2"), "{}", error);
+        assert_eq!(fs::metadata(&segment).unwrap().len(), len);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn batch_is_one_transaction_and_one_sync() {
+        let dir = temp_dir("batch");
+        let path = dir.join("wal");
+        let log = OnDiskWriteAheadLog::new(&path, Durability::Always).unwrap();
+        let server = Server::new(log);
+        let queries: Vec<String> = (0..100).map(|i| format!("SET key{} {}", i, i)).collect();
+        let queries: Vec<&str> = queries.iter().map(String::as_str).collect();
+        let replies = server
+            .execute_batch(&mut Session::default(), &queries)
+            .unwrap();
+        assert_eq!(replies.len(), 100);
+        assert_eq!(server.state.read().unwrap().len(), 100);
+
+        let mut log = server.log.into_inner().unwrap().write_ahead_log;
+        assert_eq!(log.syncs(), Some(1));
+        assert_eq!(log.replay().unwrap().count(), 1);
+        let batch: Vec<Transaction> = (1..=50).map(|id| set(id, "a", "1")).collect();
+        log.append_batch(&batch).unwrap();
+        assert_eq!(log.replay().unwrap().count(), 51);
+        assert_eq!(log.syncs(), Some(2));
+        for transaction in &batch {
+            log.append(transaction).unwrap();
+        }
+        assert_eq!(log.syncs(), Some(52));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keys_expire_by_the_injected_clock() {
+        let clock = Arc::new(clock::MockClock::new(1_000_000));
+        let server = server().with_clock(clock.clone()).with_idle_times();
+        let mut session = Session::default();
+        run(&server, &mut session, "SET a 1 EX 10");
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(
+            run(&server, &mut session, "OBJECT IDLETIME a"),
+            Reply::Int(9)
+        );
+        assert_eq!(
+            run(&server, &mut session, "GET a"),
+            Reply::Value("1".into())
+        );
+        clock.set(1_010_000);
+        assert_eq!(run(&server, &mut session, "GET a"), Reply::Nil);
+    }
+
+    #[test]
+    fn expiry_deadlines_survive_recovery() {
+        let clock = Arc::new(clock::MockClock::new(1_000_000));
+        let server = server().with_clock(clock.clone());
+        let mut session = Session::default();
+        run(&server, &mut session, "SET a 1 EX 10");
+        run(&server, &mut session, "SET b 1");
+        run(&server, &mut session, "EXPIRE b 20");
+        let deadlines = |server: &Server<InMemoryWriteAheadLog>| {
+            let state = server.state.read().unwrap();
+            ["a", "b"].map(|key| state.databases[0].kv[key].expires_at)
+        };
+        assert_eq!(deadlines(&server), [Some(1_010_000), Some(1_020_000)]);
+
+        clock.advance(Duration::from_secs(5));
+        let recovered =
+            Server::new(server.log.into_inner().unwrap().write_ahead_log).with_clock(clock.clone());
+        recovered.recover().unwrap();
+        assert_eq!(deadlines(&recovered), [Some(1_010_000), Some(1_020_000)]);
+        assert_eq!(run(&recovered, &mut session, "TTL a"), Reply::Int(5));
+        assert_eq!(run(&recovered, &mut session, "TTL b"), Reply::Int(15));
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(run(&recovered, &mut session, "GET a"), Reply::Nil);
+    }
+
+    #[test]
+    fn expire_sweep_deletes_and_logs_expired_keys() {
+        let clock = Arc::new(clock::MockClock::new(1_000_000));
+        let server = server().with_clock(clock.clone());
+        let mut session = Session::default();
+        run(&server, &mut session, "SET a 1 EX 10");
+        run(&server, &mut session, "SET b 1 EX 100");
+        run(&server, &mut session, "SET c 1");
+        assert_eq!(
+            run(&server, &mut session, "DEBUG EXPIRE-SWEEP"),
+            Reply::Int(0)
+        );
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(
+            run(&server, &mut session, "DEBUG EXPIRE-SWEEP"),
+            Reply::Int(1)
+        );
+        let keys: Vec<String> = server.state.read().unwrap().databases[0]
+            .kv
+            .keys()
+            .cloned()
+            .collect();
+        assert_eq!(keys, vec!["b", "c"]);
+        let log = server.log.lock().unwrap();
+        assert_eq!(log.write_ahead_log.data.len(), 4);
+        assert_eq!(
+            log.write_ahead_log.data[3].command,
+            Command::Delete { key: "a".into() }
+        );
+    }
+
+    #[test]
+    fn replica_follows_the_primary_log() {
+        let dir = temp_dir("replica");
+        let path = dir.join("wal");
+        let primary = Server::new(OnDiskWriteAheadLog::new(&path, Durability::Never).unwrap());
+        let mut session = Session::default();
+        run(&primary, &mut session, "SET a 1");
+        let replica = Server::new(OnDiskWriteAheadLog::open_read_only(&path, JsonCodec).unwrap())
+            .into_replica();
+        replica.recover().unwrap();
+        assert_eq!(
+            run(&replica, &mut session, "GET a"),
+            Reply::Value("1".into())
+        );
+        assert_eq!(
+            run(&replica, &mut session, "SET a 3"),
+            Reply::Error("READONLY You can't write against a read only replica".into())
+        );
+
+        run(&primary, &mut session, "SET a 2");
+        run(&primary, &mut session, "SET b 1");
+        replica.recover().unwrap();
+        assert_eq!(
+            run(&replica, &mut session, "GET a"),
+            Reply::Value("2".into())
+        );
+        assert_eq!(run(&replica, &mut session, "LASTID"), Reply::Int(2));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_increment_is_not_logged() {
+        let server = server();
+        let mut session = Session::default();
+        run(&server, &mut session, "SET a x");
+        run(&server, &mut session, &format!("SET b {}", i64::MAX));
+        assert_eq!(
+            run(&server, &mut session, "INCR a"),
+            Reply::Error(NOT_AN_INTEGER.into())
+        );
+        assert_eq!(
+            run(&server, &mut session, "INCRBY b 1"),
+            Reply::Error(OVERFLOW.into())
+        );
+        assert_eq!(run(&server, &mut session, "LASTID"), Reply::Int(1));
+        assert_eq!(server.log.lock().unwrap().write_ahead_log.data.len(), 2);
+    }
+
+    #[test]
+    fn recovers_again_without_history() {
+        let server = Server::new(InMemoryWriteAheadLog::new().without_history());
+        server.recover().unwrap();
+        let mut session = Session::default();
+        for i in 0..10 {
+            run(&server, &mut session, &format!("SET k{} v", i));
+        }
+        assert_eq!(server.log.lock().unwrap().write_ahead_log.data.len(), 1);
+        server.recover().unwrap();
+        assert_eq!(run(&server, &mut session, "DBSIZE"), Reply::Int(10));
+        assert_eq!(run(&server, &mut session, "LASTID"), Reply::Int(9));
+    }
+
+    #[test]
+    fn unlink_removes_keys_and_replays() {
+        let server = server();
+        let mut session = Session::default();
+        for _ in 0..3 {
+            run(&server, &mut session, "SET big value");
+            assert_eq!(run(&server, &mut session, "UNLINK big gone"), Reply::Int(1));
+        }
+        assert_eq!(run(&server, &mut session, "UNLINK big"), Reply::Int(0));
+        assert_eq!(run(&server, &mut session, "LASTID"), Reply::Int(5));
+
+        let data = std::mem::take(&mut server.log.lock().unwrap().write_ahead_log.data);
+        let recovered = Server::new(InMemoryWriteAheadLog {
+            data,
+            history: true,
+        });
+        recovered.recover().unwrap();
+        let state = recovered.state.read().unwrap();
+        assert_eq!(state.len(), 0);
+        assert!(state.databases.iter().all(|d| d.unlinked.is_empty()));
+    }
+
+    #[test]
+    fn load_bulk_checks_every_line() {
+        let load = |input: &str| {
+            server()
+                .with_max_sizes(MaxSizes {
+                    key_bytes: None,
+                    value_bytes: Some(3),
+                })
+                .load_bulk(input.as_bytes())
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(load("SET a 1\nGET a\nSELECT 1\nSET b 2\n"), Ok(2));
+        assert_eq!(
+            load("SET a 1\nSET b 1234\n"),
+            Err("Line 2: ERR value is larger than the maximum value size".into())
+        );
+        assert_eq!(
+            load("SET a 1\n\nRESTORE {}\n"),
+            Err("Line 3: ERR RESTORE can't be bulk loaded".into())
+        );
+    }
+
+    #[test]
+    fn transaction_applies_and_recovers_as_one() {
+        let server = server();
+        let mut session = Session::default();
+        assert_eq!(run(&server, &mut session, "MULTI"), Reply::Ok);
+        assert_eq!(run(&server, &mut session, "SET a 1"), Reply::Queued);
+        assert_eq!(run(&server, &mut session, "INCR a"), Reply::Queued);
+        assert_eq!(
+            run(&server, &mut session, "EXEC"),
+            Reply::Array(vec![Reply::Ok, Reply::Int(2)])
+        );
+        let log = server.log.into_inner().unwrap().write_ahead_log;
+        assert_eq!(log.data.len(), 1);
+
+        let recovered = Server::new(log);
+        recovered.recover().unwrap();
+        assert_eq!(
+            run(&recovered, &mut session, "GET a"),
+            Reply::Value("2".into())
+        );
+    }
+
+    #[test]
+    fn discarded_transaction_changes_nothing() {
+        let server = server();
+        let mut session = Session::default();
+        run(&server, &mut session, "MULTI");
+        run(&server, &mut session, "SET a 1");
+        assert_eq!(run(&server, &mut session, "DISCARD"), Reply::Ok);
+        assert_eq!(run(&server, &mut session, "GET a"), Reply::Nil);
+        assert_eq!(
+            run(&server, &mut session, "EXEC"),
+            Reply::Error("ERR EXEC without MULTI".into())
+        );
+    }
+
+    #[test]
+    fn transaction_fails_when_a_watched_key_changed() {
+        let server = server();
+        let (mut watcher, mut other) = (Session::default(), Session::default());
+        run(&server, &mut watcher, "WATCH a");
+        run(&server, &mut other, "SET a 1");
+        run(&server, &mut watcher, "MULTI");
+        run(&server, &mut watcher, "SET a 2");
+        assert_eq!(run(&server, &mut watcher, "EXEC"), Reply::Nil);
+        assert_eq!(
+            run(&server, &mut watcher, "GET a"),
+            Reply::Value("1".into())
+        );
+    }
+
+    #[test]
+    fn published_messages_reach_every_subscriber() {
+        let server = server();
+        let mut subscribers = [Session::default(), Session::default()];
+        for session in &mut subscribers {
+            assert_eq!(
+                run(&server, session, "SUBSCRIBE news"),
+                Reply::Array(vec![
+                    Reply::Value("subscribe".into()),
+                    Reply::Value("news".into()),
+                    Reply::Int(1),
+                ])
+            );
+        }
+        let mut publisher = Session::default();
+        assert_eq!(
+            run(&server, &mut publisher, "PUBLISH news hello"),
+            Reply::Int(2)
+        );
+        assert_eq!(
+            run(&server, &mut publisher, "PUBLISH sports hello"),
+            Reply::Int(0)
+        );
+        let receivers: Vec<Receiver<Message>> = subscribers
+            .iter_mut()
+            .map(|session| session.take_messages().unwrap())
+            .collect();
+        for messages in &receivers {
+            let message = Message {
+                channel: "news".into(),
+                message: "hello".into(),
+            };
+            assert_eq!(messages.try_recv(), Ok(message));
+            assert!(messages.try_recv().is_err());
+        }
+
+        server.pubsub.unsubscribe_all(&mut subscribers[0]);
+        assert_eq!(
+            run(&server, &mut publisher, "PUBLISH news again"),
+            Reply::Int(1)
+        );
+        assert!(receivers[0].try_recv().is_err());
+        assert!(receivers[1].try_recv().is_ok());
+    }
+
+    #[test]
+    fn seeded_random_key_is_deterministic() {
+        let picks = |seed| {
+            let server = server().with_seed(seed);
+            let mut session = Session::default();
+            assert_eq!(run(&server, &mut session, "RANDOMKEY"), Reply::Nil);
+            for key in ["a", "b", "c", "d", "e"] {
+                run(&server, &mut session, &format!("SET {} 1", key));
+            }
+            (0..5)
+                .map(|_| run(&server, &mut session, "RANDOMKEY"))
+                .collect::<Vec<_>>()
+        };
+        let value = |key: &str| Reply::Value(key.into());
+        assert_eq!(
+            picks(7),
+            vec![value("e"), value("a"), value("a"), value("d"), value("a")]
+        );
+        assert_ne!(picks(7), picks(8));
+    }
+
+    #[test]
+    fn setrange_overwrites_pads_and_recovers() {
+        let server = server();
+        let mut session = Session::default();
+        run(&server, &mut session, "SET a hello");
+        assert_eq!(
+            run(&server, &mut session, "SETRANGE a 1 ipp"),
+            Reply::Int(5)
+        );
+        assert_eq!(run(&server, &mut session, "SETRANGE b 3 xy"), Reply::Int(5));
+        assert_eq!(run(&server, &mut session, "SETRANGE a 7 !"), Reply::Int(8));
+
+        let recovered = Server::new(server.log.into_inner().unwrap().write_ahead_log);
+        recovered.recover().unwrap();
+        assert_eq!(
+            run(&recovered, &mut session, "GET a"),
+            Reply::Value("hippo\0\0!".into())
+        );
+        assert_eq!(
+            run(&recovered, &mut session, "GET b"),
+            Reply::Value("\0\0\0xy".into())
+        );
+    }
+
+    #[test]
+    fn setrange_past_the_maximum_size_is_refused() {
+        let server = server();
+        let mut session = Session::default();
+        let too_long = Reply::Error(STRING_TOO_LONG.into());
+        for offset in [usize::MAX, MAX_STRING_LEN] {
+            assert_eq!(
+                run(&server, &mut session, &format!("SETRANGE a {} x", offset)),
+                too_long
+            );
+        }
+        assert!(server.log.lock().unwrap().write_ahead_log.data.is_empty());
+        assert_eq!(run(&server, &mut session, "GET a"), Reply::Nil);
+
+        let command = Command::SetRange {
+            key: "a".into(),
+            offset: usize::MAX,
+            value: "x".into(),
+        };
+        let mut database = Database::default();
+        assert_eq!(database.apply(&command), too_long);
+        assert!(database.kv.is_empty());
+    }
+
+    #[test]
+    fn get_is_not_blocked_by_a_long_keys_scan() {
+        let server = Arc::new(server());
+        let queries: String = (0..200_000).map(|i| format!("SET k{} v\n", i)).collect();
+        server.load_bulk(queries.as_bytes()).unwrap();
+        let scan = |server: &Server<_>| {
+            let started = Instant::now();
+            run(server, &mut Session::default(), "KEYS *");
+            started.elapsed()
+        };
+        let alone = scan(&server);
+
+        let scanning = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || scan(&server))
+        };
+        thread::sleep(alone / 4);
+        let started = Instant::now();
+        assert_eq!(
+            run(&server, &mut Session::default(), "GET k1"),
+            Reply::Value("v".into())
+        );
+        let get = started.elapsed();
+        let concurrent = scanning.join().unwrap();
+        assert!(
+            get < concurrent / 4,
+            "GET took {:?} next to a KEYS taking {:?}",
+            get,
+            concurrent
+        );
+    }
+
+    #[test]
+    fn deleted_key_stays_gone_after_recovery() {
+        let server = server();
+        let mut session = Session::default();
+        run(&server, &mut session, "SET a 1");
+        run(&server, &mut session, "SET b 2");
+        assert_eq!(
+            run(&server, &mut session, "DEL a"),
+            Reply::Value("1".into())
+        );
+        assert_eq!(run(&server, &mut session, "DEL a"), Reply::Nil);
+
+        let server = recovered(server);
+        assert_eq!(run(&server, &mut session, "GET a"), Reply::Nil);
+        assert_eq!(
+            run(&server, &mut session, "GET b"),
+            Reply::Value("2".into())
+        );
+    }
+
+    #[test]
+    fn malformed_queries_are_errors() {
+        let server = server();
+        let mut session = Session::default();
+        let error = |message: &str| Reply::Error(message.into());
+        assert_eq!(run(&server, &mut session, ""), error("ERR empty command"));
+        assert_eq!(
+            run(&server, &mut session, "   "),
+            error("ERR empty command")
+        );
+        assert_eq!(
+            run(&server, &mut session, "Ste key val"),
+            error("ERR unknown command 'Ste'")
+        );
+        assert_eq!(
+            run(&server, &mut session, "SET key"),
+            error("ERR wrong number of arguments for 'SET'")
+        );
+        assert_eq!(
+            run(&server, &mut session, "GET a b"),
+            error("ERR wrong number of arguments for 'GET'")
+        );
+        assert!(server.log.lock().unwrap().write_ahead_log.data.is_empty());
+    }
+
+    #[test]
+    fn quoted_arguments_keep_whitespace_and_quotes() {
+        let server = server();
+        let mut session = Session::default();
+        let mut set_and_get = |query: &str| {
+            assert_eq!(run(&server, &mut session, query), Reply::Ok);
+            run(&server, &mut session, "GET k")
+        };
+        let value = |value: &str| Reply::Value(value.into());
+        assert_eq!(set_and_get("SET k \"hello world\""), value("hello world"));
+        assert_eq!(
+            set_and_get(r#"SET k "say \"hi\" \\o/""#),
+            value(r#"say "hi" \o/"#)
+        );
+        assert_eq!(set_and_get("SET k value  \t "), value("value"));
+        assert_eq!(set_and_get("SET k \"value  \"  "), value("value  "));
+        assert_eq!(set_and_get("SET \"k\" a\"b c\""), value("ab c"));
+        assert_eq!(
+            run(&server, &mut session, "SET k \"open"),
+            Reply::Error("ERR unbalanced quotes".into())
+        );
+    }
+
+    #[test]
+    fn only_keys_set_after_a_flush_are_recovered() {
+        let server = server();
+        let mut session = Session::default();
+        run(&server, &mut session, "SET a 1");
+        run(&server, &mut session, "SET b 1");
+        assert_eq!(run(&server, &mut session, "FLUSHALL"), Reply::Ok);
+        run(&server, &mut session, "SET b 2");
+        run(&server, &mut session, "SET c 2");
+
+        let server = recovered(server);
+        assert_eq!(run(&server, &mut session, "DBSIZE"), Reply::Int(2));
+        assert_eq!(run(&server, &mut session, "GET a"), Reply::Nil);
+        assert_eq!(
+            run(&server, &mut session, "GET b"),
+            Reply::Value("2".into())
+        );
+        assert_eq!(
+            run(&server, &mut session, "GET c"),
+            Reply::Value("2".into())
+        );
+    }
+
+    #[test]
+    fn setnx_sets_only_missing_keys() {
+        let server = server();
+        let mut session = Session::default();
+        assert_eq!(run(&server, &mut session, "SETNX a 1"), Reply::Int(1));
+        assert_eq!(run(&server, &mut session, "SETNX a 2"), Reply::Int(0));
+        assert_eq!(
+            run(&server, &mut session, "GET a"),
+            Reply::Value("1".into())
+        );
+        assert_eq!(run(&server, &mut session, "LASTID"), Reply::Int(0));
+        assert_eq!(server.log.lock().unwrap().write_ahead_log.data.len(), 1);
+        run(&server, &mut session, "SET b 1");
+        assert_eq!(run(&server, &mut session, "LASTID"), Reply::Int(1));
+    }
+
+    #[test]
+    fn rename_moves_the_value_and_recovers() {
+        let server = server();
+        let mut session = Session::default();
+        run(&server, &mut session, "SET a 1");
+        run(&server, &mut session, "SET b 2");
+        assert_eq!(run(&server, &mut session, "RENAME a b"), Reply::Ok);
+        assert_eq!(
+            run(&server, &mut session, "RENAME a c"),
+            Reply::Error("ERR no such key".into())
+        );
+        assert_eq!(server.log.lock().unwrap().write_ahead_log.data.len(), 3);
+
+        let server = recovered(server);
+        assert_eq!(run(&server, &mut session, "GET a"), Reply::Nil);
+        assert_eq!(
+            run(&server, &mut session, "GET b"),
+            Reply::Value("1".into())
+        );
+        assert_eq!(run(&server, &mut session, "DBSIZE"), Reply::Int(1));
+    }
+
+    #[test]
+    fn lrange_wraps_negative_indices() {
+        let server = server();
+        let mut session = Session::default();
+        for value in ["b", "c", "d"] {
+            run(&server, &mut session, &format!("RPUSH l {}", value));
+        }
+        assert_eq!(run(&server, &mut session, "LPUSH l a"), Reply::Int(4));
+        let lrange = |session: &mut Session, range: &str| {
+            run(&server, session, &format!("LRANGE l {}", range))
+        };
+        let values = |values: &[&str]| {
+            Reply::Array(values.iter().map(|v| Reply::Value(v.to_string())).collect())
+        };
+        assert_eq!(lrange(&mut session, "0 -1"), values(&["a", "b", "c", "d"]));
+        assert_eq!(lrange(&mut session, "-3 -2"), values(&["b", "c"]));
+        assert_eq!(lrange(&mut session, "1 100"), values(&["b", "c", "d"]));
+        assert_eq!(lrange(&mut session, "-100 0"), values(&["a"]));
+        assert_eq!(lrange(&mut session, "3 1"), values(&[]));
+        assert_eq!(lrange(&mut session, "5 10"), values(&[]));
+        assert_eq!(
+            run(&server, &mut session, "LRANGE missing 0 -1"),
+            values(&[])
+        );
+        let server = recovered(server);
+        assert_eq!(
+            run(&server, &mut session, "LRANGE l 0 -1"),
+            values(&["a", "b", "c", "d"])
+        );
+    }
+
+    #[test]
+    fn list_commands_refuse_other_types() {
+        let server = server();
+        let mut session = Session::default();
+        run(&server, &mut session, "SET s 1");
+        run(&server, &mut session, "RPUSH l a");
+        for query in ["LPUSH s a", "RPUSH s a", "LRANGE s 0 -1", "GET l"] {
+            assert_eq!(run(&server, &mut session, query), Reply::wrong_type());
+        }
+        assert_eq!(server.log.lock().unwrap().write_ahead_log.data.len(), 2);
+    }
+
+    #[test]
+    fn restoring_a_dump_brings_back_the_state() {
+        let server = server();
+        let mut session = Session::default();
+        run(&server, &mut session, "SET a \"hello world\"");
+        run(&server, &mut session, "SET b 1 EX 100");
+        run(&server, &mut session, "RPUSH l x y");
+        run(&server, &mut session, "HSET h f v");
+        let dump = run(&server, &mut session, "DUMP");
+        let Reply::Value(json) = dump.clone() else {
+            panic!("DUMP replied {:?}", dump);
+        };
+        run(&server, &mut session, "FLUSHALL");
+        assert_eq!(run(&server, &mut session, "DBSIZE"), Reply::Int(0));
+        let restore = Command::Restore { dump: json };
+        assert_eq!(
+            server.execute_command(&mut session, restore).unwrap(),
+            Reply::Ok
+        );
+        assert_eq!(run(&server, &mut session, "DUMP"), dump);
+
+        let server = recovered(server);
+        assert_eq!(run(&server, &mut session, "DUMP"), dump);
+    }
+
+    #[test]
+    fn persist_keeps_a_volatile_key() {
+        let clock = Arc::new(clock::MockClock::new(1_000_000));
+        let server = server().with_clock(clock.clone());
+        let mut session = Session::default();
+        run(&server, &mut session, "SET a 1 EX 10");
+        assert_eq!(run(&server, &mut session, "TTL a"), Reply::Int(10));
+        assert_eq!(run(&server, &mut session, "PERSIST a"), Reply::Int(1));
+        assert_eq!(run(&server, &mut session, "TTL a"), Reply::Int(-1));
+        assert_eq!(run(&server, &mut session, "PERSIST a"), Reply::Int(0));
+        assert_eq!(run(&server, &mut session, "PERSIST b"), Reply::Int(0));
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(
+            run(&server, &mut session, "GET a"),
+            Reply::Value("1".into())
+        );
+        assert!(server.state.read().unwrap().databases[0]
+            .expiries
+            .is_empty());
+    }
+
+    #[test]
+    fn expireat_in_the_past_deletes_right_away() {
+        let clock = Arc::new(clock::MockClock::new(1_000_000));
+        let server = server().with_clock(clock);
+        let mut session = Session::default();
+        run(&server, &mut session, "SET a 1");
+        assert_eq!(run(&server, &mut session, "EXPIREAT a 500"), Reply::Int(1));
+        assert!(!server.state.read().unwrap().databases[0]
+            .kv
+            .contains_key("a"));
+        assert_eq!(run(&server, &mut session, "EXPIREAT a 500"), Reply::Int(0));
+        assert_eq!(run(&server, &mut session, "EXISTS a"), Reply::Int(0));
+
+        let server = recovered(server);
+        assert_eq!(run(&server, &mut session, "DBSIZE"), Reply::Int(0));
+    }
+
+    #[test]
+    fn oversized_keys_and_values_are_rejected_unlogged() {
+        let server = server().with_max_sizes(MaxSizes {
+            key_bytes: Some(3),
+            value_bytes: Some(3),
+        });
+        let mut session = Session::default();
+        assert_eq!(run(&server, &mut session, "SET a 123"), Reply::Ok);
+        assert_eq!(
+            run(&server, &mut session, "SET a 1234"),
+            Reply::Error("ERR value is larger than the maximum value size".into())
+        );
+        assert_eq!(
+            run(&server, &mut session, "SET abcd 1"),
+            Reply::Error("ERR key is larger than the maximum key size".into())
+        );
+        assert_eq!(
+            run(&server, &mut session, "GET a"),
+            Reply::Value("123".into())
+        );
+        assert_eq!(run(&server, &mut session, "EXISTS abcd"), Reply::Int(0));
+        assert_eq!(server.log.lock().unwrap().write_ahead_log.data.len(), 1);
+    }
+
+    #[test]
+    fn cas_swaps_only_a_matching_value() {
+        let server = server();
+        let mut session = Session::default();
+        run(&server, &mut session, "SET a 1 EX 100");
+        assert_eq!(run(&server, &mut session, "CAS a 1 2"), Reply::Int(1));
+        assert_eq!(run(&server, &mut session, "CAS a 1 3"), Reply::Int(0));
+        assert_eq!(
+            run(&server, &mut session, "CAS missing \"\" 3"),
+            Reply::Int(0)
+        );
+        assert_eq!(
+            run(&server, &mut session, "GET a"),
+            Reply::Value("2".into())
+        );
+        assert_eq!(run(&server, &mut session, "EXISTS missing"), Reply::Int(0));
+        assert!(matches!(
+            run(&server, &mut session, "TTL a"),
+            Reply::Int(ttl) if ttl > 0
+        ));
+        assert_eq!(server.log.lock().unwrap().write_ahead_log.data.len(), 2);
+        assert_eq!(run(&server, &mut session, "LASTID"), Reply::Int(1));
+    }
+
+    #[test]
+    fn getex_logs_only_expiry_changes() {
+        let clock = Arc::new(clock::MockClock::new(1_000_000));
+        let server = server().with_clock(clock);
+        let mut session = Session::default();
+        run(&server, &mut session, "SET a 1");
+        let mut getex = |args: &str| {
+            let reply = run(&server, &mut session, &format!("GETEX {}", args));
+            let logged: Vec<Command> = server.log.lock().unwrap().write_ahead_log.data[1..]
+                .iter()
+                .map(|t| t.command.clone())
+                .collect();
+            (reply, logged, run(&server, &mut session, "TTL a"))
+        };
+        let getex_at = |at| Command::GetEx {
+            key: "a".into(),
+            ttl: Some(ExpiryOpt::At(at)),
+        };
+        let value = Reply::Value("1".into());
+
+        assert_eq!(getex("a"), (value.clone(), vec![], Reply::Int(-1)));
+        assert_eq!(getex("a PERSIST"), (value.clone(), vec![], Reply::Int(-1)));
+        assert_eq!(getex("missing EX 10"), (Reply::Nil, vec![], Reply::Int(-1)));
+        let mut logged = vec![getex_at(1_010_000)];
+        assert_eq!(
+            getex("a EX 10"),
+            (value.clone(), logged.clone(), Reply::Int(10))
+        );
+        logged.push(getex_at(1_005_000));
+        assert_eq!(
+            getex("a PX 5000"),
+            (value.clone(), logged.clone(), Reply::Int(5))
+        );
+        logged.push(getex_at(1_030_000));
+        assert_eq!(
+            getex("a EXAT 1030"),
+            (value.clone(), logged.clone(), Reply::Int(30))
+        );
+        logged.push(getex_at(1_020_000));
+        assert_eq!(
+            getex("a PXAT 1020000"),
+            (value.clone(), logged.clone(), Reply::Int(20))
+        );
+        assert_eq!(getex("a"), (value.clone(), logged.clone(), Reply::Int(20)));
+        logged.push(Command::GetEx {
+            key: "a".into(),
+            ttl: Some(ExpiryOpt::Persist),
+        });
+        assert_eq!(
+            getex("a PERSIST"),
+            (value.clone(), logged.clone(), Reply::Int(-1))
+        );
+        // Along with deleting the key, as one transaction
+        logged.push(Command::Batch {
+            commands: vec![
+                (0, getex_at(500_000)),
+                (0, Command::Delete { key: "a".into() }),
+            ],
+        });
+        assert_eq!(getex("a EXAT 500"), (value, logged, Reply::Int(-2)));
+    }
+
+    #[test]
+    fn lastid_advances_by_one_per_write_only() {
+        let server = server();
+        let mut session = Session::default();
+        assert_eq!(run(&server, &mut session, "LASTID"), Reply::Nil);
+        let writes = ["SET a 1", "INCR a", "APPEND a 2", "RPUSH l x", "DEL a"];
+        let reads = [
+            "GET a",
+            "EXISTS a",
+            "LRANGE l 0 -1",
+            "KEYS *",
+            "DBSIZE",
+            "LASTID",
+        ];
+        for (id, query) in writes.iter().enumerate() {
+            run(&server, &mut session, query);
+            assert_eq!(run(&server, &mut session, "LASTID"), Reply::Int(id as i64));
+            for query in reads {
+                run(&server, &mut session, query);
+            }
+            assert_eq!(run(&server, &mut session, "LASTID"), Reply::Int(id as i64));
+        }
+    }
+
+    #[test]
+    fn exec_file_runs_a_script_through_the_log() {
+        let dir = temp_dir("exec-file");
+        let path = dir.join("script.txt");
+        fs::write(&path, "SET a 1\n\nINCR a\nRPUSH l x y\nBOGUS\nSET b 2\n").unwrap();
+        let script = || BufReader::new(File::open(&path).unwrap());
+        let mut session = Session::default();
+
+        let stopped = server();
+        assert_eq!(
+            stopped
+                .exec_script(script(), false)
+                .unwrap_err()
+                .to_string(),
+            "Line 5: ERR unknown command 'BOGUS'"
+        );
+        assert_eq!(run(&stopped, &mut session, "EXISTS b"), Reply::Int(0));
+
+        let server = server();
+        assert_eq!(server.exec_script(script(), true).unwrap(), (5, 1));
+        assert_eq!(server.log.lock().unwrap().write_ahead_log.data.len(), 4);
+        let server = recovered(server);
+        assert_eq!(
+            run(&server, &mut session, "GET a"),
+            Reply::Value("2".into())
+        );
+        assert_eq!(
+            run(&server, &mut session, "LRANGE l 0 -1"),
+            Reply::Array(vec![Reply::Value("x".into()), Reply::Value("y".into())])
+        );
+        assert_eq!(
+            run(&server, &mut session, "GET b"),
+            Reply::Value("2".into())
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replay_only_prints_the_recovered_key_count() {
+        let dir = temp_dir("replay-only");
+        let (path, snapshot_path) = (dir.join("wal"), dir.join("snapshot.json"));
+        let server = Server::new(OnDiskWriteAheadLog::new(&path, Durability::Never).unwrap());
+        let mut session = Session::default();
+        for query in ["SET a 1", "SET b 1", "SET c 1"] {
+            run(&server, &mut session, query);
+        }
+        server.save_snapshot(&snapshot_path).unwrap();
+        run(&server, &mut session, "DEL a");
+        run(&server, &mut session, "SET d 1");
+        drop(server);
+
+        let summary = |snapshot_path: Option<&Path>| {
+            let mut out = vec![];
+            replay_only(&path, JsonCodec, snapshot_path, &mut out).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            let lines: Vec<&str> = out.lines().collect();
+            assert!(lines[2].starts_with("Recovered in "), "{}", out);
+            lines[..2].join("\n")
+        };
+        assert_eq!(summary(None), "3 keys\nLast transaction 4");
+        assert_eq!(summary(Some(&snapshot_path)), "3 keys\nLast transaction 4");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2, "left the files be");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unwatch_in_transaction_replies_ok() {
+        let server = server();
+        let mut session = Session::default();
+        run(&server, &mut session, "WATCH a");
+        run(&server, &mut session, "MULTI");
+        assert_eq!(run(&server, &mut session, "UNWATCH"), Reply::Queued);
+        assert_eq!(run(&server, &mut session, "SET a 1"), Reply::Queued);
+        assert_eq!(
+            run(&server, &mut session, "EXEC"),
+            Reply::Array(vec![Reply::Ok, Reply::Ok])
+        );
+    }
+}
@@ -0,0 +1,98 @@
+//! Serves the server's metrics to Prometheus over HTTP, in the text exposition format.
+
+use crate::{Server, WriteAheadLog};
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long a connection may take to send its request, or to take the response.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts a thread answering `GET /metrics` on `address`, one request per connection. Binds
+/// before returning, so a bad address fails right away.
+pub fn spawn<W>(server: Arc<Server<W>>, address: &str) -> Result<JoinHandle<()>>
+where
+    W: WriteAheadLog + Send + 'static,
+{
+    let listener = TcpListener::bind(address)?;
+    info!(address:% = listener.local_addr()?; "Serving metrics at /metrics");
+    Ok(thread::spawn(move || serve(server, listener)))
+}
+
+/// Serves every connection on a thread of its own, so a slow client doesn't hold up the others.
+fn serve<W>(server: Arc<Server<W>>, listener: TcpListener)
+where
+    W: WriteAheadLog + Send + 'static,
+{
+    for stream in listener.incoming() {
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Err(e) = stream.map_err(Into::into).and_then(|s| handle(&server, s)) {
+                warn!(error:% = e; "Failed to serve metrics");
+            }
+        });
+    }
+}
+
+fn handle<W: WriteAheadLog>(server: &Server<W>, mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers aren't needed, but are read so the client doesn't see the connection reset
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", server.metrics()?),
+        (Some(_), Some(_)) => ("404 Not Found", "Not found\n".to_string()),
+        _ => return Err(anyhow!("Invalid request {:?}", request_line)),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InMemoryWriteAheadLog, Session};
+    use std::io::Read;
+
+    #[test]
+    fn serves_metrics_past_an_idle_client() {
+        let server = Arc::new(Server::new(InMemoryWriteAheadLog::new()));
+        server.execute(&mut Session::default(), "SET a 1").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || serve(server, listener));
+
+        let _idle = TcpStream::connect(address).unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(TIMEOUT / 2)).unwrap();
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.lines().any(|line| line == "keyval_keys 1"));
+        assert!(response
+            .lines()
+            .any(|line| line == "keyval_commands_total{command=\"set\"} 1"));
+    }
+}
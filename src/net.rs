@@ -0,0 +1,435 @@
+use crate::{resp, Message, Reply, Server, Session, WriteAheadLog};
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::cell::Cell;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Wire protocol spoken to clients.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    /// RESP2, so `redis-cli` and Redis client libraries can connect
+    Resp,
+    /// One plaintext query per line, one reply per line
+    Line,
+}
+
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "resp" => Ok(Protocol::Resp),
+            "line" => Ok(Protocol::Line),
+            _ => Err(anyhow!(
+                "Unknown protocol '{}', expected 'resp' or 'line'",
+                s
+            )),
+        }
+    }
+}
+
+/// Limits protecting the server from clients that never finish a request, or send huge ones.
+/// Exceeding either closes the connection.
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionLimits {
+    /// How long a connection can stay idle, and how long a client has to send a request in full
+    /// once it started, `None` to wait forever. Connections subscribed to channels can stay idle
+    /// for longer, but not stop halfway through a request.
+    pub read_timeout: Option<Duration>,
+    /// Size in bytes of a single request, including its framing.
+    pub max_request_bytes: usize,
+}
+
+pub fn serve<W>(
+    server: Arc<Server<W>>,
+    listener: TcpListener,
+    protocol: Protocol,
+    limits: ConnectionLimits,
+) -> Result<()>
+where
+    W: WriteAheadLog + Send + 'static,
+{
+    info!(address:% = listener.local_addr()?, protocol:? = protocol; "Listening");
+
+    for stream in listener.incoming() {
+        if server.is_shutting_down() {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(error:% = e; "Failed to accept connection");
+                continue;
+            }
+        };
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Err(e) = handle(&server, stream, protocol, limits) {
+                warn!(error:% = e; "Connection closed with error");
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle<W: WriteAheadLog>(
+    server: &Server<W>,
+    stream: TcpStream,
+    protocol: Protocol,
+    limits: ConnectionLimits,
+) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    info!(peer:% = peer; "Accepted connection");
+
+    let deadline = Deadline {
+        timeout: limits.read_timeout,
+        at: Rc::default(),
+    };
+    let reader = BufReader::new(DeadlineStream {
+        stream: stream.try_clone()?,
+        deadline: deadline.clone(),
+    });
+    let mut session = Session::default();
+    let result = match protocol {
+        Protocol::Resp => handle_resp(server, &mut session, reader, stream, limits, &deadline),
+        Protocol::Line => handle_line(server, &mut session, reader, stream, limits, &deadline),
+    };
+    server.pubsub.unsubscribe_all(&mut session);
+
+    info!(peer:% = peer; "Connection closed");
+    result
+}
+
+/// When the request being read has to be read in full. Set once its first bytes arrived, so a
+/// client trickling in one byte at a time can't hold the connection forever.
+#[derive(Clone, Debug, Default)]
+struct Deadline {
+    timeout: Option<Duration>,
+    at: Rc<Cell<Option<Instant>>>,
+}
+
+impl Deadline {
+    fn start(&self) {
+        self.at
+            .set(self.timeout.map(|timeout| Instant::now() + timeout));
+    }
+
+    fn clear(&self) {
+        self.at.set(None);
+    }
+}
+
+/// Times reads out at the deadline of the request being read, or after the read timeout between
+/// requests.
+struct DeadlineStream {
+    stream: TcpStream,
+    deadline: Deadline,
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.deadline.at.get() {
+            Some(at) => match at.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => Some(remaining),
+                _ => return Err(io::ErrorKind::TimedOut.into()),
+            },
+            None => self.deadline.timeout,
+        };
+        self.stream.set_read_timeout(timeout)?;
+        self.stream.read(buffer)
+    }
+}
+
+/// Once the session subscribes, starts a thread writing the messages published to its channels
+/// to the client, through the same writer as the replies. It stops when the session is
+/// unsubscribed from all channels when the connection closes.
+fn forward_messages<T: Write + Send + 'static>(
+    session: &mut Session,
+    writer: &Arc<Mutex<T>>,
+    write: fn(&mut T, &Reply) -> Result<()>,
+) {
+    let Some(messages) = session.take_messages() else {
+        return;
+    };
+    let writer = Arc::clone(writer);
+    thread::spawn(move || forward(messages, &writer, write));
+}
+
+fn forward<T: Write>(
+    messages: Receiver<Message>,
+    writer: &Mutex<T>,
+    write: fn(&mut T, &Reply) -> Result<()>,
+) {
+    for message in messages {
+        if let Err(e) = write(&mut writer.lock().unwrap(), &message.into()) {
+            warn!(error:% = e; "Failed to forward message");
+            return;
+        }
+    }
+}
+
+/// Waits for the start of the next request, returning false once the client closed the
+/// connection. Read timeouts only close the connection when the session isn't subscribed.
+fn wait_for_request(reader: &mut impl BufRead, session: &Session) -> Result<bool> {
+    loop {
+        match reader.fill_buf() {
+            Ok(buffer) => return Ok(!buffer.is_empty()),
+            Err(e) if is_timeout(&e) && session.is_subscribed() => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn write_resp(writer: &mut impl Write, reply: &Reply) -> Result<()> {
+    resp::write_reply(writer, reply)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_line(writer: &mut impl Write, reply: &Reply) -> Result<()> {
+    writeln!(writer, "{}", encode(reply))?;
+    Ok(())
+}
+
+fn handle_resp<W: WriteAheadLog>(
+    server: &Server<W>,
+    session: &mut Session,
+    mut reader: impl BufRead,
+    writer: impl Write + Send + 'static,
+    limits: ConnectionLimits,
+    deadline: &Deadline,
+) -> Result<()> {
+    let writer = Arc::new(Mutex::new(BufWriter::new(writer)));
+    loop {
+        if !wait_for_request(&mut reader, session)? {
+            return Ok(());
+        }
+        deadline.start();
+        let request = resp::read_request(&mut reader, limits.max_request_bytes);
+        deadline.clear();
+        let arguments = match request {
+            Ok(Some(arguments)) => arguments,
+            Ok(None) => return Ok(()),
+            // Like Redis, report the protocol error and drop the connection, as there's no telling
+            // where the next request starts
+            Err(e) => {
+                write_resp(&mut *writer.lock().unwrap(), &Reply::Error(e.to_string()))?;
+                return Err(e);
+            }
+        };
+        if arguments.is_empty() {
+            continue;
+        }
+
+        let reply = Server::<W>::parse_tokens(&arguments)
+            .inspect_err(|_| session.abort())
+            .and_then(|command| server.execute_for_client(session, command))
+            .unwrap_or_else(|e| Reply::Error(e.to_string()));
+        write_resp(&mut *writer.lock().unwrap(), &reply)?;
+        forward_messages(session, &writer, write_resp);
+    }
+}
+
+/// Blank lines, including ones with only whitespace, are skipped without a reply. A line the
+/// client didn't terminate before closing the connection might have been cut short, so it's
+/// answered with an error instead of executed. So is a line longer than the maximum request
+/// size, which closes the connection as soon as that many bytes have been read.
+fn handle_line<W: WriteAheadLog>(
+    server: &Server<W>,
+    session: &mut Session,
+    mut reader: impl BufRead,
+    writer: impl Write + Send + 'static,
+    limits: ConnectionLimits,
+    deadline: &Deadline,
+) -> Result<()> {
+    let writer = Arc::new(Mutex::new(writer));
+    let max = limits.max_request_bytes;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if !wait_for_request(&mut reader, session)? {
+            break;
+        }
+        deadline.start();
+        let read = reader.by_ref().take(max as u64 + 1).read_line(&mut line);
+        deadline.clear();
+        read?;
+        if line.len() > max {
+            let message = "ERR request is larger than the maximum request size";
+            writeln!(writer.lock().unwrap(), "{}", message)?;
+            return Err(anyhow!(message));
+        }
+        // Also strips the carriage return of clients ending lines with "\r\n"
+        let query = match line.strip_suffix('\n') {
+            Some(query) => query.strip_suffix('\r').unwrap_or(query),
+            None => {
+                writeln!(writer.lock().unwrap(), "ERR unterminated line")?;
+                break;
+            }
+        };
+        if query.trim().is_empty() {
+            continue;
+        }
+        let reply = Server::<W>::parse(query)
+            .inspect_err(|_| session.abort())
+            .and_then(|command| server.execute_for_client(session, command))
+            .unwrap_or_else(|e| Reply::Error(e.to_string()));
+        write_line(&mut *writer.lock().unwrap(), &reply)?;
+        forward_messages(session, &writer, write_line);
+    }
+    Ok(())
+}
+
+/// Encodes a reply for the line protocol. Values are quoted, so they can be told apart from
+/// integers, nil and each other; array elements go on separate lines.
+fn encode(reply: &Reply) -> String {
+    match reply {
+        Reply::Ok => "OK".into(),
+        Reply::Queued => "QUEUED".into(),
+        Reply::Pong => "PONG".into(),
+        Reply::Nil => "(nil)".into(),
+        Reply::Value(value) => format!("{:?}", value),
+        Reply::Int(value) => value.to_string(),
+        Reply::Error(message) => message.clone(),
+        Reply::Array(items) if items.is_empty() => "(empty array)".into(),
+        Reply::Array(items) => items.iter().map(encode).collect::<Vec<_>>().join("\n"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InMemoryWriteAheadLog;
+    use std::sync::atomic::Ordering;
+
+    /// A writer the test can still read after handing it to a handler.
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buffer)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const LIMITS: ConnectionLimits = ConnectionLimits {
+        read_timeout: Some(Duration::from_millis(300)),
+        max_request_bytes: 16,
+    };
+
+    #[test]
+    fn oversized_line_closes_the_connection() {
+        let server = Server::new(InMemoryWriteAheadLog::new());
+        let input = format!("SET a 1\nSET b {}\nGET a\n", "x".repeat(1000));
+        let mut reader = BufReader::with_capacity(16, input.as_bytes());
+        let output = Output::default();
+        let result = handle_line(
+            &server,
+            &mut Session::default(),
+            &mut reader,
+            output.clone(),
+            LIMITS,
+            &Deadline::default(),
+        );
+        assert!(result.is_err());
+        let written = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            written,
+            "OK\nERR request is larger than the maximum request size\n"
+        );
+        // Stopped reading right after the limit, rather than buffering the whole line
+        assert!(reader.into_inner().len() > 900);
+    }
+
+    /// Runs the line protocol over `input`, returning what was written back.
+    fn converse(server: &Server<InMemoryWriteAheadLog>, input: &str) -> String {
+        let output = Output::default();
+        handle_line(
+            server,
+            &mut Session::default(),
+            input.as_bytes(),
+            output.clone(),
+            LIMITS,
+            &Deadline::default(),
+        )
+        .unwrap();
+        let written = output.0.lock().unwrap().clone();
+        String::from_utf8(written).unwrap()
+    }
+
+    #[test]
+    fn blank_lines_are_skipped() {
+        let server = Server::new(InMemoryWriteAheadLog::new());
+        assert_eq!(converse(&server, ""), "");
+        assert_eq!(converse(&server, "\n  \t\n\r\n \r\n"), "");
+        assert!(server.log.lock().unwrap().write_ahead_log.data.is_empty());
+        assert_eq!(converse(&server, "\nSET a 1\n\n  \nLASTID\n"), "OK\n0\n");
+    }
+
+    #[test]
+    fn carriage_returns_end_lines() {
+        let server = Server::new(InMemoryWriteAheadLog::new());
+        assert_eq!(
+            converse(&server, "SET a 1\r\nGET a\r\n\r\nLASTID\r\n"),
+            "OK\n\"1\"\n0\n"
+        );
+    }
+
+    #[test]
+    fn only_ping_is_answered_while_recovering() {
+        let server = Server::new(InMemoryWriteAheadLog::new());
+        server.recovering.store(true, Ordering::SeqCst);
+        let output = Output::default();
+        handle_line(
+            &server,
+            &mut Session::default(),
+            "PING\nPING hi\nGET a\n".as_bytes(),
+            output.clone(),
+            LIMITS,
+            &Deadline::default(),
+        )
+        .unwrap();
+        let written = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            written,
+            "PONG\n\"hi\"\nLOADING server is recovering its state\n"
+        );
+    }
+
+    #[test]
+    fn trickled_request_times_out() {
+        let server = Arc::new(Server::new(InMemoryWriteAheadLog::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let handler = thread::spawn(move || handle(&server, stream, Protocol::Line, LIMITS));
+
+        let started = Instant::now();
+        for byte in b"GET a" {
+            if client.write_all(&[*byte]).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert!(handler.join().unwrap().is_err());
+        assert!(started.elapsed() < Duration::from_millis(600));
+    }
+}
@@ -0,0 +1,104 @@
+//! The subset of RESP2 needed to talk to Redis clients: requests are arrays of bulk strings, and
+//! replies map onto simple strings, errors, integers, bulk strings and arrays.
+
+use crate::Reply;
+use anyhow::{anyhow, Result};
+use std::io::{self, BufRead, Read, Write};
+
+// Same limits Redis uses by default
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// Reads a single request, returning `None` once the client closed the connection. Requests
+/// larger than `max_len` bytes are rejected before more than `max_len` bytes are read or
+/// allocated.
+pub fn read_request(reader: &mut impl BufRead, max_len: usize) -> Result<Option<Vec<String>>> {
+    let mut remaining = max_len;
+    let Some(count) = read_header(reader, '*', &mut remaining)? else {
+        return Ok(None);
+    };
+    if count > MAX_ARGUMENTS {
+        return Err(anyhow!("ERR Protocol error: invalid multibulk length"));
+    }
+    // Every argument takes at least the 6 bytes of "$0\r\n\r\n"
+    if count.saturating_mul(6) > remaining {
+        return Err(too_large());
+    }
+
+    let mut arguments = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len = match read_header(reader, '$', &mut remaining)? {
+            Some(len) if len <= MAX_BULK_LEN => len,
+            Some(_) => return Err(anyhow!("ERR Protocol error: invalid bulk length")),
+            None => return Err(anyhow!("ERR Protocol error: unexpected end of request")),
+        };
+        if len + 2 > remaining {
+            return Err(too_large());
+        }
+        remaining -= len + 2;
+        let mut bulk = vec![0; len + 2];
+        reader.read_exact(&mut bulk)?;
+        if !bulk.ends_with(b"\r\n") {
+            return Err(anyhow!(
+                "ERR Protocol error: expected '\\r\\n' after bulk string"
+            ));
+        }
+        bulk.truncate(len);
+        let argument = String::from_utf8(bulk)
+            .map_err(|_| anyhow!("ERR Protocol error: bulk string is not valid UTF-8"))?;
+        arguments.push(argument);
+    }
+    Ok(Some(arguments))
+}
+
+/// Reads a `<prefix><length>\r\n` line, returning `None` at the end of the stream. Reads at most
+/// `remaining` bytes, and subtracts the ones read.
+fn read_header(
+    reader: &mut impl BufRead,
+    prefix: char,
+    remaining: &mut usize,
+) -> Result<Option<usize>> {
+    if *remaining == 0 {
+        return Err(too_large());
+    }
+    let mut line = String::new();
+    let read = reader
+        .by_ref()
+        .take(*remaining as u64)
+        .read_line(&mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    *remaining -= read;
+    if !line.ends_with('\n') && *remaining == 0 {
+        return Err(too_large());
+    }
+    let length = line
+        .strip_suffix("\r\n")
+        .and_then(|line| line.strip_prefix(prefix))
+        .ok_or_else(|| anyhow!("ERR Protocol error: expected '{}', got {:?}", prefix, line))?;
+    length
+        .parse()
+        .map(Some)
+        .map_err(|_| anyhow!("ERR Protocol error: invalid length {:?}", length))
+}
+
+fn too_large() -> anyhow::Error {
+    anyhow!("ERR Protocol error: request is larger than the maximum request size")
+}
+
+pub fn write_reply(writer: &mut impl Write, reply: &Reply) -> io::Result<()> {
+    match reply {
+        Reply::Ok => write!(writer, "+OK\r\n"),
+        Reply::Queued => write!(writer, "+QUEUED\r\n"),
+        Reply::Pong => write!(writer, "+PONG\r\n"),
+        Reply::Nil => write!(writer, "$-1\r\n"),
+        Reply::Value(value) => write!(writer, "${}\r\n{}\r\n", value.len(), value),
+        Reply::Int(value) => write!(writer, ":{}\r\n", value),
+        Reply::Error(message) => write!(writer, "-{}\r\n", message.replace(['\r', '\n'], " ")),
+        Reply::Array(items) => {
+            write!(writer, "*{}\r\n", items.len())?;
+            items.iter().try_for_each(|item| write_reply(writer, item))
+        }
+    }
+}